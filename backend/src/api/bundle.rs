@@ -0,0 +1,106 @@
+use crate::api::APIError;
+use crate::parsing::{bundle_archive, get_unique_folder, AppendWriter};
+use crate::util::data;
+use crate::util::database::BeatMap;
+use crate::util::warp::{get_content_disposition, get_map};
+use crate::util::LockResultExt;
+use anyhow::Error;
+use futures::channel::mpsc;
+use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use warp::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use warp::http::Response;
+use warp::hyper::Body;
+use warp::{reply, Rejection, Reply};
+use zip::ZipWriter;
+
+pub const MAX_BUNDLE_MAPS: usize = 100;
+// Bundles are streamed, but this still bounds how long one request keeps the server busy
+pub const MAX_BUNDLE_SIZE: i64 = 500000000;
+// How many maps are looked up at once
+const LOOKUPS: usize = 16;
+// How many archives are downloaded ahead of the client, each can be up to MAX_SIZE
+const DOWNLOADS: usize = 4;
+
+#[derive(Debug, Deserialize)]
+pub struct BundleRequest {
+    pub maps: Vec<String>,
+}
+
+pub async fn bundle(request: BundleRequest) -> Result<impl Reply, Rejection> {
+    let ids: Vec<String> = request.maps.into_iter().collect::<HashSet<_>>().into_iter().collect();
+    if ids.is_empty() || ids.len() > MAX_BUNDLE_MAPS {
+        return Err(APIError::BundleSizeError().into());
+    }
+    let maps: Vec<BeatMap> = stream::iter(ids).map(get_map).buffer_unordered(LOOKUPS).try_collect().await?;
+    Ok(bundle_maps(maps, "Beatblock Bundle.zip").await?)
+}
+
+/// Zips several maps together, each in a folder named like its download.
+/// The zip is sent while it's built, so only a few archives are held at once.
+pub async fn bundle_maps(mut maps: Vec<BeatMap>, download_name: &str) -> Result<impl Reply, APIError> {
+    if maps.is_empty() || maps.len() > MAX_BUNDLE_MAPS {
+        return Err(APIError::BundleSizeError());
+    }
+    maps.sort_by_key(BeatMap::get_download_name);
+
+    // Checked before anything is sent, since the response can't be turned into an error after that
+    let keys: Vec<String> = maps.iter().map(BeatMap::get_archive_key).collect();
+    let sizes: Vec<Option<i64>> = stream::iter(keys.clone())
+        .map(|key| async move { data().await.amazon.get_object_size(&key).await })
+        .buffer_unordered(LOOKUPS)
+        .try_collect()
+        .await
+        .map_err(APIError::database_error)?;
+    if sizes.into_iter().flatten().sum::<i64>() > MAX_BUNDLE_SIZE {
+        return Err(APIError::BundleSizeError());
+    }
+
+    let mut folders = HashSet::new();
+    let archives: Vec<(String, String)> = maps
+        .iter()
+        .zip(keys)
+        .map(|(map, key)| {
+            let name = map.get_download_name();
+            // Two maps can have the same artist, song and charter
+            (get_unique_folder(name.trim_end_matches(".zip"), &mut folders), key)
+        })
+        .collect();
+    let (mut sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        if let Err(err) = send_bundle(archives, &mut sender).await {
+            println!("Failed to send bundle: {err:?}");
+            // Ends the response early, so the client doesn't keep a broken zip
+            let _ = sender.send(Err(err)).await;
+        }
+    });
+    Ok(reply::with_header(
+        reply::with_header(Response::new(Body::wrap_stream(receiver)), CONTENT_TYPE, "application/zip"),
+        CONTENT_DISPOSITION,
+        get_content_disposition(download_name),
+    ))
+}
+
+// Sends the zip a map at a time, waiting on the client before downloading more
+async fn send_bundle(
+    archives: Vec<(String, String)>,
+    sender: &mut mpsc::Sender<Result<Vec<u8>, Error>>,
+) -> Result<(), Error> {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut zip = ZipWriter::new(AppendWriter::new(output.clone()));
+    let mut downloads = stream::iter(archives)
+        .map(|(folder, key)| async move { Ok::<_, Error>((folder, data().await.amazon.get_object(&key).await?)) })
+        .buffered(DOWNLOADS);
+    while let Some((folder, archive)) = downloads.try_next().await? {
+        bundle_archive(&mut zip, &folder, &archive)?;
+        let written = mem::take(&mut *output.lock().ignore_poison());
+        sender.send(Ok(written)).await?;
+    }
+    zip.finish()?;
+    let written = mem::take(&mut *output.lock().ignore_poison());
+    sender.send(Ok(written)).await?;
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use crate::api::bundle::{bundle_maps, MAX_BUNDLE_MAPS};
+use crate::api::APIError;
+use crate::util::amazon::{is_condition_failure, to_attribute, COLLECTIONS_TABLE_NAME, MAPS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{BeatMap, Collection, MapID, User, UserID};
+use crate::util::warp::{get_map, get_user, Replyable};
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+// A whole collection can always be downloaded as one bundle
+pub const MAX_COLLECTION_MAPS: usize = MAX_BUNDLE_MAPS;
+pub const MAX_COLLECTION_NAME_LENGTH: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCollectionRequest {
+    token: String,
+    name: String,
+    #[serde(default)]
+    public: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditCollectionRequest {
+    token: String,
+    name: Option<String>,
+    public: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectionMapRequest {
+    token: String,
+    #[serde(rename = "mapId")]
+    map_id: String,
+    // Where to insert the map, the end if not set
+    position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReorderRequest {
+    token: String,
+    maps: Vec<MapID>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteCollectionRequest {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct CollectionResult {
+    #[serde(flatten)]
+    pub collection: Collection,
+    // In the collection's order, deleted maps are left out
+    pub results: Vec<BeatMap>,
+}
+
+pub async fn create_collection(request: CreateCollectionRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token).await?;
+    let collection = Collection {
+        id: Uuid::new_v4(),
+        owner: user.id,
+        name: check_name(&request.name)?,
+        public: request.public,
+        maps: vec![],
+        create_date: DateTime::<Utc>::from(SystemTime::now()),
+        update_date: DateTime::<Utc>::from(SystemTime::now()),
+    };
+    data().await.amazon
+        .upload(COLLECTIONS_TABLE_NAME, &collection, None::<&Vec<String>>)
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(collection.reply())
+}
+
+/// Public collections can be viewed by anyone with the link, private ones only by their owner.
+pub async fn collection(id: String, user: Option<User>) -> Result<impl Reply, Rejection> {
+    let collection = get_visible_collection(id, user.as_ref()).await?;
+    let results = get_maps(&collection).await?;
+    Ok(CollectionResult {
+        collection,
+        results,
+    }.reply())
+}
+
+/// The user's collections, private ones are only included for the user themselves.
+pub async fn user_collections(id: String, user: Option<User>) -> Result<impl Reply, Rejection> {
+    let mut collections: Vec<Collection> = data().await.amazon
+        .query(COLLECTIONS_TABLE_NAME, "owner", id)
+        .await
+        .map_err(APIError::database_error)?;
+    collections.retain(|collection| collection.public || user.as_ref().is_some_and(|user| user.id == collection.owner));
+    collections.sort_by_key(|collection| Reverse(collection.update_date));
+    Ok(collections.reply())
+}
+
+pub async fn edit_collection(id: String, request: EditCollectionRequest) -> Result<impl Reply, Rejection> {
+    let name = request.name.as_deref().map(check_name).transpose()?;
+    let collection = update_collection(id, &request.token, |collection| {
+        if let Some(name) = &name {
+            collection.name = name.clone();
+        }
+        if let Some(public) = request.public {
+            collection.public = public;
+        }
+        Ok(())
+    }).await?;
+    Ok(collection.reply())
+}
+
+pub async fn add_to_collection(id: String, request: CollectionMapRequest) -> Result<impl Reply, Rejection> {
+    let map = get_map(request.map_id).await?;
+    let collection = update_collection(id, &request.token, |collection| {
+        if collection.maps.contains(&map.id) {
+            return Err(APIError::AlreadyInCollection());
+        }
+        if collection.maps.len() >= MAX_COLLECTION_MAPS {
+            return Err(APIError::CollectionSizeError());
+        }
+        let position = request.position.unwrap_or(collection.maps.len()).min(collection.maps.len());
+        collection.maps.insert(position, map.id);
+        Ok(())
+    }).await?;
+    Ok(collection.reply())
+}
+
+pub async fn remove_from_collection(id: String, request: CollectionMapRequest) -> Result<impl Reply, Rejection> {
+    let collection = update_collection(id, &request.token, |collection| {
+        let position = collection.maps.iter().position(|map| map.to_string() == request.map_id)
+            .ok_or(APIError::ArgumentError())?;
+        collection.maps.remove(position);
+        Ok(())
+    }).await?;
+    Ok(collection.reply())
+}
+
+/// Replaces the order of the maps, the new order must have exactly the same maps.
+pub async fn reorder_collection(id: String, request: ReorderRequest) -> Result<impl Reply, Rejection> {
+    let collection = update_collection(id, &request.token, |collection| {
+        let old: HashSet<&MapID> = collection.maps.iter().collect();
+        let new: HashSet<&MapID> = request.maps.iter().collect();
+        if old != new || new.len() != request.maps.len() {
+            return Err(APIError::ArgumentError());
+        }
+        collection.maps = request.maps.clone();
+        Ok(())
+    }).await?;
+    Ok(collection.reply())
+}
+
+pub async fn delete_collection(id: String, request: DeleteCollectionRequest) -> Result<impl Reply, Rejection> {
+    let collection = get_owned_collection(id, &request.token).await?;
+    data().await.amazon
+        .remove(COLLECTIONS_TABLE_NAME, "id", collection.id.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok("Ok".reply())
+}
+
+pub async fn download_collection(id: String, user: Option<User>) -> Result<impl Reply, Rejection> {
+    let collection = get_visible_collection(id, user.as_ref()).await?;
+    let maps = get_maps(&collection).await?;
+    Ok(bundle_maps(maps, &format!("{}.zip", collection.name)).await?)
+}
+
+// In the collection's order, deleted maps are left out
+async fn get_maps(collection: &Collection) -> Result<Vec<BeatMap>, APIError> {
+    let maps: Vec<BeatMap> = data().await.amazon
+        .get_items(MAPS_TABLE_NAME, collection.maps.iter().map(ToString::to_string).collect())
+        .await
+        .map_err(APIError::database_error)?;
+    let mut maps: HashMap<MapID, BeatMap> = maps.into_iter().map(|map| (map.id, map)).collect();
+    Ok(collection.maps.iter().filter_map(|id| maps.remove(id)).collect())
+}
+
+/// Moves the user's collections to another account, for when accounts are merged.
+pub async fn reassign_collections(from: &UserID, to: &UserID) -> Result<(), APIError> {
+    let collections: Vec<Collection> = data().await.amazon
+        .query(COLLECTIONS_TABLE_NAME, "owner", from.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    for collection in collections {
+        data().await.amazon
+            .set_fields(COLLECTIONS_TABLE_NAME, collection.id.to_string(), vec![("owner", AttributeValue::S(to.to_string()))])
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+async fn get_collection(id: String) -> Result<Collection, APIError> {
+    data().await.amazon
+        .get_item(COLLECTIONS_TABLE_NAME, id)
+        .await
+        .map_err(APIError::database_error)?
+        .ok_or(APIError::ArgumentError())
+}
+
+async fn get_visible_collection(id: String, user: Option<&User>) -> Result<Collection, APIError> {
+    let collection = get_collection(id).await?;
+    if !collection.public && user.is_none_or(|user| user.id != collection.owner) {
+        return Err(APIError::PermissionError());
+    }
+    Ok(collection)
+}
+
+async fn get_owned_collection(id: String, token: &str) -> Result<Collection, APIError> {
+    let user = get_user(token.to_string()).await?;
+    let collection = get_collection(id).await?;
+    if collection.owner != user.id {
+        return Err(APIError::PermissionError());
+    }
+    Ok(collection)
+}
+
+// Edits are only saved if nothing else saved the collection since it was read, otherwise they're
+// made again on the newer collection
+async fn update_collection<F: Fn(&mut Collection) -> Result<(), APIError>>(
+    id: String,
+    token: &str,
+    edit: F,
+) -> Result<Collection, APIError> {
+    loop {
+        let mut collection = get_owned_collection(id.clone(), token).await?;
+        let read_date = to_attribute(&collection.update_date)?;
+        edit(&mut collection)?;
+        collection.update_date = DateTime::<Utc>::from(SystemTime::now());
+        let saved = data().await.amazon
+            .upload_if(COLLECTIONS_TABLE_NAME, &collection, "update_date = :read", vec![(":read", read_date)])
+            .await;
+        match saved {
+            Ok(()) => return Ok(collection),
+            Err(err) if is_condition_failure(&err) => continue,
+            Err(err) => return Err(APIError::database_error(err)),
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<String, APIError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_COLLECTION_NAME_LENGTH {
+        return Err(APIError::ArgumentError());
+    }
+    Ok(name.to_string())
+}
@@ -0,0 +1,351 @@
+use crate::api::delete::ADMINS;
+use crate::api::APIError;
+use crate::util::amazon::{is_condition_failure, to_attribute, COMMENTS_TABLE_NAME};
+use crate::util::database::{Comment, CommentID, MapID, UserID};
+use crate::util::ratelimiter::{SiteAction, UniqueIdentifier};
+use crate::util::warp::{get_map, get_user, Replyable};
+use crate::util::{data, LockResultExt};
+use anyhow::Error;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+pub const MAX_COMMENT_LENGTH: usize = 2000;
+// Deeper threads can't be read on a phone anyway
+pub const MAX_REPLY_DEPTH: u32 = 8;
+
+#[derive(Debug, Deserialize)]
+pub struct CommentRequest {
+    token: String,
+    text: String,
+    // The comment being replied to
+    parent: Option<CommentID>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditCommentRequest {
+    token: String,
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteCommentRequest {
+    token: String,
+}
+
+// The comment's own `replies` is its count, so the replies themselves are its children
+#[derive(Serialize, Deserialize)]
+pub struct CommentThread {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub children: Vec<CommentThread>,
+}
+
+/// The map's comments as threads, oldest first.
+pub async fn comments(id: String) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    let mut comments = get_comments(&map.id).await?;
+    comments.sort_by_key(|comment| comment.post_date);
+    Ok(build_threads(comments).reply())
+}
+
+// Comments are grouped by parent first, so each is only looked at once. Replies whose parent
+// is missing are shown at the top instead of being dropped.
+fn build_threads(comments: Vec<Comment>) -> Vec<CommentThread> {
+    let ids: HashSet<CommentID> = comments.iter().map(|comment| comment.id).collect();
+    let mut children: HashMap<Option<CommentID>, Vec<Comment>> = HashMap::new();
+    for comment in comments {
+        let parent = comment.parent.filter(|parent| ids.contains(parent));
+        children.entry(parent).or_default().push(comment);
+    }
+    add_replies(None, &mut children)
+}
+
+// Replies can only be nested MAX_REPLY_DEPTH deep, so this doesn't recurse further than that
+fn add_replies(parent: Option<CommentID>, children: &mut HashMap<Option<CommentID>, Vec<Comment>>) -> Vec<CommentThread> {
+    children
+        .remove(&parent)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|comment| CommentThread {
+            children: add_replies(Some(comment.id), children),
+            comment,
+        })
+        .collect()
+}
+
+pub async fn post_comment(id: String, request: CommentRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token).await?;
+    let map = get_map(id).await?;
+    let text = check_text(&request.text)?;
+    let parent = match &request.parent {
+        Some(parent) => Some(get_comment(&map.id.to_string(), parent).await?),
+        None => None,
+    };
+    if parent.as_ref().is_some_and(|parent| parent.depth >= MAX_REPLY_DEPTH) {
+        return Err(APIError::KnownArgumentError(Error::msg(format!("Replies can't be nested more than {MAX_REPLY_DEPTH} deep"))).into());
+    }
+    data().await.ratelimiter
+        .lock()
+        .ignore_poison()
+        .check_limited(SiteAction::Comment, &UniqueIdentifier::User(user.id))?;
+
+    // Counted on the parent before the reply exists, so deleting the parent always sees it
+    if let Some(parent) = &parent {
+        data().await.amazon
+            .update(COMMENTS_TABLE_NAME, parent.id.to_string(), |builder| {
+                builder
+                    .update_expression("SET replies = if_not_exists(replies, :zero) + :one")
+                    .condition_expression("attribute_exists(id)")
+                    .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+                    .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            })
+            .await
+            .map_err(|err| match is_condition_failure(&err) {
+                true => APIError::ArgumentError(),
+                false => APIError::database_error(err),
+            })?;
+    }
+
+    let comment = Comment {
+        id: Uuid::new_v4(),
+        map_id: map.id,
+        author: user.id,
+        parent: request.parent,
+        depth: parent.map_or(0, |parent| parent.depth + 1),
+        replies: 0,
+        text,
+        post_date: DateTime::<Utc>::from(SystemTime::now()),
+        edit_date: None,
+        deleted: false,
+    };
+    data().await.amazon
+        .upload(COMMENTS_TABLE_NAME, &comment, None::<&Vec<String>>)
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(comment.reply())
+}
+
+/// Only the author can edit their comment.
+pub async fn edit_comment(map: String, id: CommentID, request: EditCommentRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token).await?;
+    let mut comment = get_comment(&map, &id).await?;
+    if comment.author != user.id || comment.deleted {
+        return Err(APIError::PermissionError().into());
+    }
+    data().await.ratelimiter
+        .lock()
+        .ignore_poison()
+        .check_limited(SiteAction::Comment, &UniqueIdentifier::User(user.id))?;
+
+    comment.text = check_text(&request.text)?;
+    comment.edit_date = Some(DateTime::<Utc>::from(SystemTime::now()));
+    data().await.amazon
+        .set_fields(COMMENTS_TABLE_NAME, comment.id.to_string(), vec![
+            ("text", AttributeValue::S(comment.text.clone())),
+            ("edit_date", to_attribute(&comment.edit_date)?),
+        ])
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(comment.reply())
+}
+
+/// The author, the map's charter and admins can delete comments. Comments with replies are
+/// blanked instead so the thread stays readable.
+pub async fn delete_comment(map: String, id: CommentID, request: DeleteCommentRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token).await?;
+    let comment = get_comment(&map, &id).await?;
+    let map = get_map(comment.map_id.to_string()).await?;
+    if comment.author != user.id && map.charter_uid != user.id && !ADMINS.contains(&user.id.to_string().as_str()) {
+        return Err(APIError::PermissionError().into());
+    }
+
+    // The reply count is read with the delete, so a reply posted in between blanks the comment instead
+    let removed = data().await.amazon
+        .remove_if(COMMENTS_TABLE_NAME, comment.id.to_string(), "attribute_not_exists(replies) OR replies = :zero",
+                   vec![(":zero", AttributeValue::N("0".to_string()))])
+        .await;
+    match removed {
+        Ok(()) => {
+            if let Some(parent) = &comment.parent {
+                remove_reply(parent).await?;
+            }
+        }
+        Err(err) if is_condition_failure(&err) => {
+            data().await.amazon
+                .set_fields(COMMENTS_TABLE_NAME, comment.id.to_string(), vec![
+                    ("text", AttributeValue::S(String::new())),
+                    ("deleted", AttributeValue::Bool(true)),
+                ])
+                .await
+                .map_err(APIError::database_error)?;
+        }
+        Err(err) => return Err(APIError::database_error(err).into()),
+    }
+    Ok("Ok".reply())
+}
+
+// A blanked comment is removed with its last reply, which can leave its own parent empty in turn
+async fn remove_reply(parent: &CommentID) -> Result<(), APIError> {
+    let mut parent = *parent;
+    loop {
+        let updated = data().await.amazon
+            .update_returning(COMMENTS_TABLE_NAME, parent.to_string(), |builder| {
+                builder
+                    .update_expression("SET replies = replies - :one")
+                    .condition_expression("replies > :zero")
+                    .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+                    .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            })
+            .await;
+        let comment: Comment = match updated {
+            Ok(item) => serde_dynamo::from_item(item).map_err(APIError::database_error)?,
+            Err(err) if is_condition_failure(&err) => return Ok(()),
+            Err(err) => return Err(APIError::database_error(err)),
+        };
+        if !comment.deleted || comment.replies > 0 {
+            return Ok(());
+        }
+        // Replies are counted before they're posted, so one posted since keeps the comment
+        let removed = data().await.amazon
+            .remove_if(COMMENTS_TABLE_NAME, comment.id.to_string(), "replies = :zero",
+                       vec![(":zero", AttributeValue::N("0".to_string()))])
+            .await;
+        match removed {
+            Ok(()) => {}
+            Err(err) if is_condition_failure(&err) => return Ok(()),
+            Err(err) => return Err(APIError::database_error(err)),
+        }
+        let Some(next) = comment.parent else {
+            return Ok(());
+        };
+        parent = next;
+    }
+}
+
+/// Removes every comment on the map, for when it's deleted.
+pub async fn delete_comments(map: &MapID) -> Result<(), APIError> {
+    for comment in get_comments(map).await? {
+        data().await.amazon
+            .remove(COMMENTS_TABLE_NAME, "id", comment.id.to_string())
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+/// Moves the user's comments to another account, for when accounts are merged.
+pub async fn reassign_comments(from: &UserID, to: &UserID) -> Result<(), APIError> {
+    let comments: Vec<Comment> = data().await.amazon
+        .query(COMMENTS_TABLE_NAME, "author", from.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    for comment in comments {
+        data().await.amazon
+            .set_fields(COMMENTS_TABLE_NAME, comment.id.to_string(), vec![("author", AttributeValue::S(to.to_string()))])
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+async fn get_comments(map: &MapID) -> Result<Vec<Comment>, APIError> {
+    data().await.amazon
+        .query(COMMENTS_TABLE_NAME, "map_id", map.to_string())
+        .await
+        .map_err(APIError::database_error)
+}
+
+// Comments are only found through the map they're on
+async fn get_comment(map: &str, id: &CommentID) -> Result<Comment, APIError> {
+    data().await.amazon
+        .get_item::<Comment>(COMMENTS_TABLE_NAME, id.to_string())
+        .await
+        .map_err(APIError::database_error)?
+        .filter(|comment| comment.map_id.to_string() == map)
+        .ok_or(APIError::ArgumentError())
+}
+
+fn check_text(text: &str) -> Result<String, APIError> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_COMMENT_LENGTH {
+        return Err(APIError::CommentLengthError());
+    }
+    Ok(text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: u128, parent: Option<u128>) -> Comment {
+        Comment {
+            id: Uuid::from_u128(id),
+            map_id: Uuid::nil(),
+            author: Uuid::nil(),
+            parent: parent.map(Uuid::from_u128),
+            depth: 0,
+            replies: 0,
+            text: id.to_string(),
+            post_date: DateTime::<Utc>::from_timestamp(id as i64, 0).unwrap(),
+            edit_date: None,
+            deleted: false,
+        }
+    }
+
+    fn texts(threads: &[CommentThread]) -> Vec<String> {
+        threads.iter().map(|thread| thread.comment.text.clone()).collect()
+    }
+
+    #[test]
+    fn replies_are_nested_under_their_parents_in_order() {
+        let threads = build_threads(vec![
+            comment(1, None),
+            comment(2, Some(1)),
+            comment(3, None),
+            comment(4, Some(2)),
+            comment(5, Some(1)),
+        ]);
+        assert_eq!(texts(&threads), vec!["1", "3"]);
+        assert_eq!(texts(&threads[0].children), vec!["2", "5"]);
+        assert_eq!(texts(&threads[0].children[0].children), vec!["4"]);
+        assert!(threads[1].children.is_empty());
+    }
+
+    #[test]
+    fn orphaned_replies_are_kept_at_the_top() {
+        let threads = build_threads(vec![comment(1, None), comment(2, Some(99)), comment(3, Some(2))]);
+        assert_eq!(texts(&threads), vec!["1", "2"]);
+        assert_eq!(texts(&threads[1].children), vec!["3"]);
+    }
+
+    #[test]
+    fn threads_as_deep_as_allowed_are_built() {
+        let last = MAX_REPLY_DEPTH as u128 + 1;
+        let comments: Vec<Comment> = (1..=last).map(|id| comment(id, (id > 1).then(|| id - 1))).collect();
+        let threads = build_threads(comments);
+        let mut depth = 0;
+        let mut thread = &threads[0];
+        while let Some(reply) = thread.children.first() {
+            thread = reply;
+            depth += 1;
+        }
+        assert_eq!(depth, MAX_REPLY_DEPTH);
+    }
+
+    #[test]
+    fn threads_keep_the_reply_count_apart_from_the_replies() {
+        let mut parent = comment(1, None);
+        parent.replies = 1;
+        let threads = build_threads(vec![parent, comment(2, Some(1))]);
+        let json = serde_json::to_string(&threads).unwrap();
+        let read: Vec<CommentThread> = serde_json::from_str(&json).unwrap();
+        assert_eq!(read[0].comment.replies, 1);
+        assert_eq!(texts(&read[0].children), vec!["2"]);
+        assert!(read[0].children[0].children.is_empty());
+    }
+}
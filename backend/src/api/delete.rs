@@ -1,8 +1,15 @@
 use warp::{Rejection, Reply};
 use crate::api::APIError;
+use crate::api::comments::delete_comments;
+use crate::api::feed::unpublish;
+use crate::api::flags::remove_hashes;
+use crate::api::notifications::schedule_notifications;
 use crate::util::amazon::{MAPS_TABLE_NAME, USERS_TABLE_NAME};
-use crate::util::data;
-use crate::util::database::{BeatMap, User};
+use crate::util::archive::release_archive;
+use crate::util::{data, schedule_pack_update};
+use crate::util::database::{get_diff_key, get_timeline_key, BeatMap, NotificationEvent, User};
+use crate::util::image::{IMAGE_SIZES, OUTPUT_FORMATS};
+use crate::util::share::get_share_card_key;
 use crate::util::warp::Replyable;
 
 pub const ADMINS: [&'static str; 1] = ["gfde6dkqtey5trmfya8h"];
@@ -19,7 +26,27 @@ pub async fn delete(
     data().await.amazon.remove(MAPS_TABLE_NAME, "id", map.id.to_string()).await
         .map_err(APIError::database_error)?;
     data().await.amazon.overwrite_list(USERS_TABLE_NAME, user.id.to_string(), "maps", user.maps).await?;
-    data().await.amazon.delete_object(format!("{}.zip", map.id).as_str()).await.map_err(APIError::database_error)?;
-    data().await.amazon.delete_object(format!("{}.png", map.id).as_str()).await.map_err(APIError::database_error)?;
+    release_archive(&map).await?;
+    delete_comments(&map.id).await?;
+    remove_hashes(&map).await?;
+    if let Some(pack_id) = &map.pack_id {
+        schedule_pack_update(*pack_id);
+    }
+    if let Err(err) = unpublish(&map).await {
+        println!("Failed to remove {} from feeds: {err:?}", map.id);
+    }
+    schedule_notifications(map.clone(), NotificationEvent::Delete, user.id);
+    for size in IMAGE_SIZES {
+        for format in OUTPUT_FORMATS {
+            data().await.amazon.delete_object(size.get_key(&map.id, format).as_str()).await.map_err(APIError::database_error)?;
+        }
+    }
+    data().await.amazon.delete_object(get_share_card_key(&map.id).as_str()).await.map_err(APIError::database_error)?;
+    for version in map.versions.iter().filter(|version| version.changes.is_some()) {
+        data().await.amazon.delete_object(get_diff_key(&map.id, version.version).as_str()).await.map_err(APIError::database_error)?;
+    }
+    for (i, _) in map.difficulties.iter().enumerate().filter(|(_, variant)| variant.timeline) {
+        data().await.amazon.delete_object(get_timeline_key(&map.id, i).as_str()).await.map_err(APIError::database_error)?;
+    }
     Ok("Ok".reply())
 }
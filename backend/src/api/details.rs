@@ -0,0 +1,42 @@
+use crate::api::APIError;
+use crate::util::amazon::MAPS_TABLE_NAME;
+use crate::util::data;
+use crate::util::database::{BeatMap, User};
+use crate::util::warp::{get_map, Replyable};
+use serde::Serialize;
+use std::cmp::Reverse;
+use warp::{Rejection, Reply};
+
+#[derive(Serialize)]
+pub struct MapDetails {
+    #[serde(flatten)]
+    pub map: BeatMap,
+    pub charter_maps: Vec<BeatMap>,
+    pub archive_size: Option<i64>,
+    // Only set when the request is signed in
+    pub upvoted: Option<bool>,
+    pub downvoted: Option<bool>,
+    pub downloaded: Option<bool>,
+}
+
+pub async fn map_details(id: String, user: Option<User>) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    let mut charter_maps: Vec<BeatMap> = data().await.amazon
+        .query(MAPS_TABLE_NAME, "charter_uid", map.charter_uid.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    charter_maps.retain(|other| other.id != map.id);
+    charter_maps.sort_by_key(|other| Reverse(other.upload_date));
+    let archive_size = data().await.amazon
+        .get_object_size(map.get_archive_key().as_str())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(MapDetails {
+        upvoted: user.as_ref().map(|user| user.upvoted.contains(&map.id)),
+        downvoted: user.as_ref().map(|user| user.downvoted.contains(&map.id)),
+        downloaded: user.as_ref().map(|user| user.downloaded.contains(&map.id)),
+        map,
+        charter_maps,
+        archive_size,
+    }.reply())
+}
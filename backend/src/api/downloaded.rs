@@ -1,9 +1,49 @@
-use warp::{Rejection, Reply};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use warp::http::Uri;
+use warp::{redirect, Rejection, Reply};
 use crate::api::APIError;
-use crate::util::amazon::USERS_TABLE_NAME;
+use crate::api::flags::check_admin;
+use crate::api::stats::record_stat;
+use crate::util::amazon::{DOWNLOADS_TABLE_NAME, MAPS_TABLE_NAME, USERS_TABLE_NAME};
 use crate::util::data;
-use crate::util::database::{BeatMap, User};
-use crate::util::warp::Replyable;
+use crate::util::database::{BeatMap, Download, MapID, User, UserID};
+use crate::util::ratelimiter::UniqueIdentifier;
+use crate::util::warp::{get_map, Replyable};
+
+// Downloading the same map again within this long isn't counted again
+pub const DOWNLOAD_COUNT_WINDOW: Duration = Duration::from_secs(60 * 60 * 24);
+
+lazy_static! {
+    static ref COUNTED_DOWNLOADS: Mutex<CountedDownloads> = Mutex::new(CountedDownloads::default());
+}
+
+/// The downloads counted within the window, by the account or address that made them.
+#[derive(Default)]
+struct CountedDownloads {
+    counted: HashSet<(UniqueIdentifier, MapID)>,
+    order: VecDeque<(Instant, UniqueIdentifier, MapID)>,
+}
+
+impl CountedDownloads {
+    /// Whether the download should be counted, remembering it if so.
+    fn count(&mut self, identifier: UniqueIdentifier, map: MapID, now: Instant) -> bool {
+        while let Some((time, identifier, map)) = self.order.front() {
+            if now.duration_since(*time) < DOWNLOAD_COUNT_WINDOW {
+                break;
+            }
+            self.counted.remove(&(*identifier, *map));
+            self.order.pop_front();
+        }
+        if !self.counted.insert((identifier, map)) {
+            return false;
+        }
+        self.order.push_back((now, identifier, map));
+        true
+    }
+}
 
 pub async fn download(
     user: User,
@@ -13,6 +53,7 @@ pub async fn download(
         return Err(APIError::AlreadyDownloaded().into());
     }
     data().await.amazon.add_to_list(USERS_TABLE_NAME, user.id.to_string(), "downloaded", map.id.to_string()).await?;
+    index_downloads(&user.id, &[map.id]).await?;
     Ok("Ok".reply())
 }
 
@@ -22,5 +63,116 @@ pub async fn remove(
 ) -> Result<impl Reply, Rejection> {
     user.downloaded.remove(user.downloaded.iter().position(|elem| elem == &map.id).ok_or(APIError::AlreadyUpvoted())?);
     data().await.amazon.overwrite_list(USERS_TABLE_NAME, user.id.to_string(), "downloaded", user.downloaded).await?;
+    data().await.amazon.remove(DOWNLOADS_TABLE_NAME, "id", get_download_id(&map.id, &user.id)).await
+        .map_err(APIError::database_error)?;
     Ok("Ok".reply())
 }
+
+/// Redirects to a presigned link to the archive, which S3 serves with range support for resuming.
+/// Only requests starting from the beginning are counted, so resumes don't count twice,
+/// and each account or address is counted once per map within [`DOWNLOAD_COUNT_WINDOW`].
+pub async fn download_archive(
+    id: String,
+    range: Option<String>,
+    identifier: UniqueIdentifier,
+    user: Option<User>,
+) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    let identifier = user.as_ref().map_or(identifier, |user| UniqueIdentifier::User(user.id));
+    if range.is_none_or(|range| range.trim().starts_with("bytes=0-"))
+        && COUNTED_DOWNLOADS.lock().unwrap().count(identifier, map.id, Instant::now()) {
+        data().await.amazon.increment(MAPS_TABLE_NAME, map.id.to_string(), "downloads").await
+            .map_err(APIError::database_error)?;
+        record_stat(&map, "downloads", 1).await?;
+        if let Some(user) = user.filter(|user| !user.downloaded.contains(&map.id)) {
+            data().await.amazon.add_to_list(USERS_TABLE_NAME, user.id.to_string(), "downloaded", map.id.to_string()).await?;
+            index_downloads(&user.id, &[map.id]).await?;
+        }
+    }
+    let url = data().await.amazon.presign_object(map.get_archive_key().as_str(), &map.get_download_name()).await
+        .map_err(APIError::database_error)?;
+    Ok(redirect::temporary(Uri::try_from(url).map_err(APIError::database_error)?))
+}
+
+/// The users who downloaded the map.
+pub async fn get_downloaders(map: &MapID) -> Result<Vec<UserID>, APIError> {
+    let downloads: Vec<Download> = data().await.amazon.query(DOWNLOADS_TABLE_NAME, "map_id", map.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(downloads.into_iter().map(|download| download.user_id).collect())
+}
+
+/// Drops a deleted map's downloads from the index.
+pub async fn remove_downloads(map: &MapID) -> Result<(), APIError> {
+    let downloads: Vec<Download> = data().await.amazon.query(DOWNLOADS_TABLE_NAME, "map_id", map.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    data().await.amazon.remove_all(DOWNLOADS_TABLE_NAME, downloads.into_iter().map(|download| download.id).collect())
+        .await
+        .map_err(APIError::database_error)
+}
+
+/// Moves the downloads of an account being merged away to the account it's merged into.
+pub async fn reassign_downloads(from: &UserID, to: &UserID, maps: &[MapID]) -> Result<(), APIError> {
+    index_downloads(to, maps).await?;
+    data().await.amazon
+        .remove_all(DOWNLOADS_TABLE_NAME, get_unique(maps).iter().map(|map| get_download_id(map, from)).collect())
+        .await
+        .map_err(APIError::database_error)
+}
+
+/// Indexes every download from before downloads were indexed.
+pub async fn index_all_downloads(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    let users: Vec<User> = data().await.amazon.scan(USERS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    for user in &users {
+        index_downloads(&user.id, &user.downloaded).await?;
+    }
+    Ok(format!("Indexed {} downloads", users.iter().map(|user| user.downloaded.len()).sum::<usize>()).reply())
+}
+
+fn get_download_id(map: &MapID, user: &UserID) -> String {
+    format!("{map}_{user}")
+}
+
+async fn index_downloads(user: &UserID, maps: &[MapID]) -> Result<(), APIError> {
+    let downloads: Vec<Download> = get_unique(maps)
+        .into_iter()
+        .map(|map| Download {
+            id: get_download_id(&map, user),
+            map_id: map,
+            user_id: *user,
+        })
+        .collect();
+    data().await.amazon.upload_all(DOWNLOADS_TABLE_NAME, &downloads).await.map_err(APIError::database_error)
+}
+
+// A batch can't write the same item twice, and old lists can have repeats
+fn get_unique(maps: &[MapID]) -> Vec<MapID> {
+    let mut maps = maps.to_vec();
+    maps.sort();
+    maps.dedup();
+    maps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    #[test]
+    fn downloads_are_counted_once_per_window() {
+        let mut downloads = CountedDownloads::default();
+        let (map, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let address = UniqueIdentifier::Ipv4(Ipv4Addr::LOCALHOST);
+        let user = UniqueIdentifier::User(Uuid::new_v4());
+        let start = Instant::now();
+        assert!(downloads.count(address, map, start));
+        assert!(!downloads.count(address, map, start + Duration::from_secs(60)));
+        assert!(downloads.count(address, other, start));
+        assert!(downloads.count(user, map, start));
+        assert!(downloads.count(address, map, start + DOWNLOAD_COUNT_WINDOW));
+    }
+}
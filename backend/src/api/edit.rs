@@ -0,0 +1,132 @@
+use crate::api::flags::check_admin;
+use crate::api::APIError;
+use crate::parsing::diff::FieldChange;
+use crate::util::amazon::{is_condition_failure, to_attribute, EDITS_TABLE_NAME, MAPS_TABLE_NAME};
+use crate::util::database::{BeatMap, MapEdit};
+use crate::util::image::ImageSize;
+use crate::util::share::{get_share_card_key, render_share_card};
+use crate::util::warp::{get_map, get_user, Replyable};
+use crate::util::{data, get_search_combos};
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use image::ImageFormat;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct EditRequest {
+    token: String,
+    song: Option<String>,
+    artist: Option<String>,
+    artist_list: Option<String>,
+    description: Option<String>,
+}
+
+/// Edits the map's metadata in place, leaving the archive as it is.
+/// The song name can't be edited, since re-uploads are matched to maps by it.
+pub async fn edit_map(id: String, request: EditRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token.clone()).await?;
+    let mut map = get_map(id).await?;
+    if map.charter_uid != user.id {
+        check_admin(&user)?;
+    }
+    if request.song.as_ref().is_some_and(|song| song.trim() != map.song) {
+        return Err(APIError::KnownArgumentError(Error::msg("The song name can only be changed by uploading the map again")).into());
+    }
+
+    // Uploads and other edits since the map was read fail the write, and it's redone on the newer map
+    let changes = loop {
+        let read_date = to_attribute(&map.update_date)?;
+        let changes = apply_changes(&mut map, &request);
+        if changes.is_empty() {
+            return Ok(map.reply());
+        }
+        map.update_date = DateTime::<Utc>::from(SystemTime::now());
+        let mut fields = vec![
+            ("title_prefix", to_attribute(&get_search_combos(&map))?),
+            ("update_date", to_attribute(&map.update_date)?),
+        ];
+        for change in &changes {
+            fields.push((change.field.as_str(), to_attribute(&change.new)?));
+        }
+        match data().await.amazon
+            .set_fields_if(MAPS_TABLE_NAME, map.id.to_string(), fields, "update_date = :read", vec![(":read", read_date)])
+            .await
+        {
+            Ok(()) => break changes,
+            Err(err) if is_condition_failure(&err) => {
+                map = data().await.amazon.get_item(MAPS_TABLE_NAME, map.id.to_string())
+                    .await
+                    .map_err(APIError::database_error)?
+                    .ok_or(APIError::AuthError("Invalid map!".to_string()))?;
+            }
+            Err(err) => return Err(APIError::database_error(err).into()),
+        }
+    };
+    data().await.amazon
+        .upload(EDITS_TABLE_NAME, &MapEdit {
+            id: Uuid::new_v4(),
+            map_id: map.id,
+            editor: user.id,
+            changes,
+            edit_date: map.update_date,
+        }, None::<&Vec<String>>)
+        .await
+        .map_err(APIError::database_error)?;
+
+    if map.share_card {
+        update_share_card(&map).await;
+    }
+    Ok(map.reply())
+}
+
+// Sets the requested fields on the map, returning what actually changed
+fn apply_changes(map: &mut BeatMap, request: &EditRequest) -> Vec<FieldChange> {
+    let mut changes = vec![];
+    for (field, value, new) in [
+        ("artist", &mut map.artist, &request.artist),
+        ("artist_list", &mut map.artist_list, &request.artist_list),
+        ("description", &mut map.description, &request.description),
+    ] {
+        let Some(new) = new.as_ref().map(|new| new.trim().to_string()) else {
+            continue;
+        };
+        if new == *value {
+            continue;
+        }
+        changes.push(FieldChange {
+            field: field.to_string(),
+            old: Value::String(value.clone()),
+            new: Value::String(new.clone()),
+        });
+        *value = new;
+    }
+    changes
+}
+
+// The card shows the song and artist, so it's redrawn over the stored background
+async fn update_share_card(map: &BeatMap) {
+    let background = match map.image {
+        true => data().await.amazon
+            .get_object(ImageSize::Full.get_key(&map.id, ImageFormat::Png).as_str())
+            .await
+            .and_then(|image| Ok(image::load_from_memory(&image)?))
+            .map_err(|err| println!("Failed to load background for {}: {err:?}", map.id))
+            .ok(),
+        false => None,
+    };
+    let share_card = match render_share_card(map, background.as_ref()) {
+        Ok(Some(share_card)) => share_card,
+        Ok(None) => return,
+        Err(err) => {
+            println!("Failed to render share card for {}: {err:?}", map.id);
+            return;
+        }
+    };
+    if let Err(err) = data().await.amazon.upload_object(share_card, get_share_card_key(&map.id).as_str()).await {
+        println!("Failed to upload share card for {}: {err:?}", map.id);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::api::flags::check_admin;
+use crate::api::APIError;
+use crate::util::amazon::{to_attribute, FEED_TABLE_NAME, FOLLOWS_TABLE_NAME, MAPS_TABLE_NAME, USERS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{sortable_date, BeatMap, FeedEvent, FeedKind, Follow, MapID, User, UserID};
+use crate::util::warp::Replyable;
+use anyhow::Error;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use warp::{Rejection, Reply};
+
+pub const FEED_PAGE_SIZE: i32 = 25;
+pub const MAX_FOLLOWING: usize = 500;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FeedOptions {
+    // The `next` cursor of the page before
+    pub after: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FeedEntry {
+    pub kind: FeedKind,
+    pub date: DateTime<Utc>,
+    // Newer than the last time the user marked their feed as read
+    pub unread: bool,
+    // Set for updates
+    pub version: Option<u32>,
+    pub map: BeatMap,
+}
+
+#[derive(Serialize)]
+pub struct FeedResult {
+    pub results: Vec<FeedEntry>,
+    pub unread: usize,
+    pub next: Option<String>,
+}
+
+pub async fn follow(mut user: User, charter: String) -> Result<impl Reply, Rejection> {
+    let charter: UserID = charter.parse().map_err(|_| APIError::ArgumentError())?;
+    if charter == user.id || user.following.contains(&charter) {
+        return Err(APIError::ArgumentError().into());
+    }
+    if user.following.len() >= MAX_FOLLOWING {
+        return Err(APIError::KnownArgumentError(Error::msg("Can't follow more charters")).into());
+    }
+    let _: User = data().await.amazon.query_one(USERS_TABLE_NAME, "id", charter.to_string())
+        .await
+        .map_err(APIError::database_error)?
+        .ok_or(APIError::KnownArgumentError(Error::msg("No user with that id")))?;
+    user.following.push(charter);
+    data().await.amazon.overwrite_list(USERS_TABLE_NAME, user.id.to_string(), "following", user.following).await?;
+    add_follow(&user.id, &charter).await?;
+    Ok("Ok".reply())
+}
+
+pub async fn unfollow(mut user: User, charter: String) -> Result<impl Reply, Rejection> {
+    let position = user.following.iter().position(|followed| followed.to_string() == charter)
+        .ok_or(APIError::ArgumentError())?;
+    let charter = user.following.remove(position);
+    data().await.amazon.overwrite_list(USERS_TABLE_NAME, user.id.to_string(), "following", user.following).await?;
+    remove_follow(&user.id, &charter).await?;
+    Ok("Ok".reply())
+}
+
+/// New uploads and updates from the charters the user follows, newest first.
+pub async fn feed(user: Option<User>, options: FeedOptions) -> Result<impl Reply, Rejection> {
+    let user = user.ok_or(APIError::AuthError("Missing token!".to_string()))?;
+    let (events, next): (Vec<FeedEvent>, _) = data().await.amazon
+        .query_page(FEED_TABLE_NAME, "user_id", user.id.to_string(), None, options.after, FEED_PAGE_SIZE)
+        .await?;
+    let unread = data().await.amazon
+        .count(FEED_TABLE_NAME, "user_id", user.id.to_string(),
+               user.feed_read.as_ref().map(|read| AttributeValue::S(sortable_date::format(read))), None)
+        .await
+        .map_err(APIError::database_error)?;
+
+    let mut ids: Vec<String> = events.iter().map(|event| event.map_id.to_string()).collect();
+    ids.sort();
+    ids.dedup();
+    let maps: HashMap<MapID, BeatMap> = data().await.amazon.get_items(MAPS_TABLE_NAME, ids)
+        .await
+        .map_err(APIError::database_error)?
+        .into_iter()
+        .map(|map: BeatMap| (map.id, map))
+        .collect();
+    Ok(FeedResult {
+        results: get_entries(events, &maps, user.feed_read),
+        unread,
+        next,
+    }.reply())
+}
+
+/// Marks everything currently in the user's feed as read.
+pub async fn read_feed(user: User) -> Result<impl Reply, Rejection> {
+    let now = DateTime::<Utc>::from(SystemTime::now());
+    data().await.amazon
+        .set_fields(USERS_TABLE_NAME, user.id.to_string(), vec![("feed_read", to_attribute(&Some(now))?)])
+        .await
+        .map_err(APIError::database_error)?;
+    Ok("Ok".reply())
+}
+
+/// The users following the charter.
+pub async fn get_followers(charter: &UserID) -> Result<Vec<UserID>, APIError> {
+    let follows: Vec<Follow> = data().await.amazon.query(FOLLOWS_TABLE_NAME, "charter", charter.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(follows.into_iter().map(|follow| follow.follower).collect())
+}
+
+/// Adds the map's newest version to its charter's followers' feeds.
+pub async fn publish(map: &BeatMap) -> Result<(), APIError> {
+    let events: Vec<FeedEvent> = get_followers(&map.charter_uid)
+        .await?
+        .iter()
+        .filter_map(|follower| get_events(follower, map).pop())
+        .collect();
+    data().await.amazon.upload_all(FEED_TABLE_NAME, &events).await.map_err(APIError::database_error)
+}
+
+/// Takes a deleted map out of its charter's followers' feeds.
+pub async fn unpublish(map: &BeatMap) -> Result<(), APIError> {
+    let ids: Vec<String> = get_followers(&map.charter_uid)
+        .await?
+        .iter()
+        .flat_map(|follower| get_events(follower, map))
+        .map(|event| event.id)
+        .collect();
+    data().await.amazon.remove_all(FEED_TABLE_NAME, ids).await.map_err(APIError::database_error)
+}
+
+/// Moves the follows of an account being merged away, both the charters it followed and its followers.
+/// Has to run before its maps are moved, so its followers' feeds are only rebuilt for the maps they don't have.
+pub async fn reassign_follows(from: &User, to: &User) -> Result<(), APIError> {
+    for charter in &from.following {
+        remove_follow(&from.id, charter).await?;
+        if charter != &to.id && !to.following.contains(charter) {
+            add_follow(&to.id, charter).await?;
+        }
+    }
+    for follower in get_followers(&from.id).await? {
+        data().await.amazon.remove(FOLLOWS_TABLE_NAME, "id", get_follow_id(&from.id, &follower))
+            .await
+            .map_err(APIError::database_error)?;
+        // The merged account keeps its own list, which drops both accounts when merging
+        if follower == to.id {
+            continue;
+        }
+        let Some(mut user): Option<User> = data().await.amazon.get_item(USERS_TABLE_NAME, follower.to_string())
+            .await
+            .map_err(APIError::database_error)? else {
+            continue;
+        };
+        user.following.retain(|charter| charter != &from.id && charter != &to.id);
+        user.following.push(to.id);
+        data().await.amazon.overwrite_list(USERS_TABLE_NAME, follower.to_string(), "following", user.following).await?;
+        add_follow(&follower, &to.id).await?;
+    }
+    Ok(())
+}
+
+/// Moves what's left in a merged account's feed, after its follows were moved.
+pub async fn reassign_feed(from: &UserID, to: &UserID) -> Result<(), APIError> {
+    let mut cursor = None;
+    loop {
+        let (events, next): (Vec<FeedEvent>, _) = data().await.amazon
+            .query_page(FEED_TABLE_NAME, "user_id", from.to_string(), None, cursor, FEED_PAGE_SIZE)
+            .await?;
+        let ids: Vec<String> = events.iter().map(|event| event.id.clone()).collect();
+        let moved: Vec<FeedEvent> = events.into_iter()
+            .map(|event| FeedEvent {
+                id: event.id.replacen(&from.to_string(), &to.to_string(), 1),
+                user_id: *to,
+                ..event
+            })
+            .collect();
+        data().await.amazon.upload_all(FEED_TABLE_NAME, &moved).await.map_err(APIError::database_error)?;
+        data().await.amazon.remove_all(FEED_TABLE_NAME, ids).await.map_err(APIError::database_error)?;
+        let Some(next) = next else {
+            return Ok(());
+        };
+        cursor = Some(next);
+    }
+}
+
+/// Indexes every follow from before follows were indexed.
+pub async fn index_all_follows(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    let users: Vec<User> = data().await.amazon.scan(USERS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    let mut follows = 0;
+    for user in &users {
+        for charter in &user.following {
+            add_follow(&user.id, charter).await?;
+            follows += 1;
+        }
+    }
+    Ok(format!("Indexed {follows} follows").reply())
+}
+
+fn get_follow_id(charter: &UserID, follower: &UserID) -> String {
+    format!("{charter}_{follower}")
+}
+
+// Indexes the follow and fills the follower's feed with the newest version of each map the charter
+// already uploaded, so following doesn't bury the feed under old updates
+async fn add_follow(follower: &UserID, charter: &UserID) -> Result<(), APIError> {
+    let follow = Follow {
+        id: get_follow_id(charter, follower),
+        charter: *charter,
+        follower: *follower,
+    };
+    data().await.amazon.upload(FOLLOWS_TABLE_NAME, &follow, None::<&Vec<String>>)
+        .await
+        .map_err(APIError::database_error)?;
+    let events: Vec<FeedEvent> = get_charter_maps(charter)
+        .await?
+        .iter()
+        .filter_map(|map| get_events(follower, map).pop())
+        .collect();
+    data().await.amazon.upload_all(FEED_TABLE_NAME, &events).await.map_err(APIError::database_error)
+}
+
+async fn remove_follow(follower: &UserID, charter: &UserID) -> Result<(), APIError> {
+    data().await.amazon.remove(FOLLOWS_TABLE_NAME, "id", get_follow_id(charter, follower))
+        .await
+        .map_err(APIError::database_error)?;
+    let ids: Vec<String> = get_charter_maps(charter)
+        .await?
+        .iter()
+        .flat_map(|map| get_events(follower, map))
+        .map(|event| event.id)
+        .collect();
+    data().await.amazon.remove_all(FEED_TABLE_NAME, ids).await.map_err(APIError::database_error)
+}
+
+async fn get_charter_maps(charter: &UserID) -> Result<Vec<BeatMap>, APIError> {
+    data().await.amazon.query(MAPS_TABLE_NAME, "charter_uid", charter.to_string())
+        .await
+        .map_err(APIError::database_error)
+}
+
+// Every version of the map as it shows up in the user's feed, the first one is its upload
+fn get_events(user: &UserID, map: &BeatMap) -> Vec<FeedEvent> {
+    map.get_versions()
+        .into_iter()
+        .enumerate()
+        .map(|(i, version)| FeedEvent {
+            id: format!("{user}_{}_{}", map.id, version.version),
+            user_id: *user,
+            map_id: map.id,
+            kind: if i == 0 { FeedKind::Upload } else { FeedKind::Update },
+            version: (i > 0).then_some(version.version),
+            date: version.upload_date,
+        })
+        .collect()
+}
+
+// Events of maps deleted since they were added are left out, maps are only copied for the one page
+fn get_entries(events: Vec<FeedEvent>, maps: &HashMap<MapID, BeatMap>, read: Option<DateTime<Utc>>) -> Vec<FeedEntry> {
+    events.into_iter()
+        .filter_map(|event| Some(FeedEntry {
+            kind: event.kind,
+            date: event.date,
+            unread: read.is_none_or(|read| event.date > read),
+            version: event.version,
+            map: maps.get(&event.map_id)?.clone(),
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::database::MapVersion;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn get_version(version: u32, day: u32) -> MapVersion {
+        MapVersion {
+            version,
+            archive_hash: None,
+            changelog: String::new(),
+            upload_date: Utc.with_ymd_and_hms(2024, 5, day, 0, 0, 0).unwrap(),
+            changes: None,
+        }
+    }
+
+    #[test]
+    fn upload_comes_before_updates() {
+        let user = Uuid::new_v4();
+        let map = BeatMap {
+            id: Uuid::new_v4(),
+            versions: vec![get_version(1, 1), get_version(2, 3), get_version(3, 5)],
+            ..Default::default()
+        };
+        let events = get_events(&user, &map);
+        assert_eq!(events.iter().map(|event| event.kind).collect::<Vec<_>>(),
+                   vec![FeedKind::Upload, FeedKind::Update, FeedKind::Update]);
+        assert_eq!(events.iter().map(|event| event.version).collect::<Vec<_>>(), vec![None, Some(2), Some(3)]);
+        assert_eq!(events[2].id, format!("{user}_{}_3", map.id));
+        assert_eq!(events[2].date, get_version(3, 5).upload_date);
+    }
+
+    #[test]
+    fn maps_from_before_versioning_are_one_upload() {
+        let map = BeatMap { id: Uuid::new_v4(), ..Default::default() };
+        let events = get_events(&Uuid::new_v4(), &map);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, FeedKind::Upload);
+        assert_eq!(events[0].date, map.upload_date);
+    }
+
+    #[test]
+    fn entries_keep_the_page_order_without_deleted_maps() {
+        let user = Uuid::new_v4();
+        let kept = BeatMap {
+            id: Uuid::new_v4(),
+            versions: vec![get_version(1, 1), get_version(2, 5)],
+            ..Default::default()
+        };
+        let deleted = BeatMap { id: Uuid::new_v4(), versions: vec![get_version(1, 3)], ..Default::default() };
+        let mut events = get_events(&user, &kept);
+        events.extend(get_events(&user, &deleted));
+        events.sort_by_key(|event| std::cmp::Reverse(event.date));
+
+        let maps = HashMap::from([(kept.id, kept.clone())]);
+        let entries = get_entries(events, &maps, Some(get_version(1, 2).upload_date));
+        assert_eq!(entries.iter().map(|entry| (entry.version, entry.unread)).collect::<Vec<_>>(),
+                   vec![(Some(2), true), (None, false)]);
+        assert!(entries.iter().all(|entry| entry.map.id == kept.id));
+    }
+
+    #[test]
+    fn dates_sort_as_text() {
+        let date = get_version(1, 1).upload_date;
+        let dates = [date, date + chrono::TimeDelta::microseconds(123456), date + chrono::TimeDelta::milliseconds(500)];
+        let texts: Vec<String> = dates.iter().map(sortable_date::format).collect();
+        let mut sorted = texts.clone();
+        sorted.sort();
+        assert_eq!(texts, sorted);
+    }
+
+    #[test]
+    fn everything_is_unread_before_reading() {
+        let map = BeatMap { id: Uuid::new_v4(), ..Default::default() };
+        let maps = HashMap::from([(map.id, map.clone())]);
+        let entries = get_entries(get_events(&Uuid::new_v4(), &map), &maps, None);
+        assert!(entries[0].unread);
+    }
+}
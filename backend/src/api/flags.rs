@@ -0,0 +1,226 @@
+use crate::api::delete::ADMINS;
+use crate::api::APIError;
+use crate::util::amazon::{FLAGS_TABLE_NAME, HASHES_TABLE_NAME, MAPS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{BeatMap, ContentHash, DuplicateFlag, User};
+use crate::util::warp::Replyable;
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+// Backgrounds whose hashes differ by at most this many bits count as the same image
+pub const MAX_IMAGE_DISTANCE: u32 = 6;
+// Backgrounds are indexed by each byte of their hash. Similar ones differ in at most
+// MAX_IMAGE_DISTANCE of the bytes, so they always share at least one
+const IMAGE_BANDS: u32 = 8;
+
+pub async fn flags(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    let mut flags: Vec<DuplicateFlag> = data().await.amazon.scan(FLAGS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    flags.sort_by(|first, second| first.flag_date.cmp(&second.flag_date).reverse());
+    Ok(flags.reply())
+}
+
+pub async fn dismiss_flag(user: User, flag: String) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    data().await.amazon.remove(FLAGS_TABLE_NAME, "id", flag).await
+        .map_err(APIError::database_error)?;
+    Ok("Ok".reply())
+}
+
+pub fn check_admin(user: &User) -> Result<(), APIError> {
+    if !ADMINS.contains(&user.id.to_string().as_str()) {
+        return Err(APIError::PermissionError());
+    }
+    Ok(())
+}
+
+/// Flags the map for review if it looks like a reupload of another charter's map.
+/// Only maps sharing a chart, audio file or similar background are compared, found through the hash index.
+pub async fn check_duplicates(map: &BeatMap, old: Option<&BeatMap>) -> Result<(), APIError> {
+    index_hashes(map, old).await?;
+    let mut candidates = HashSet::new();
+    for hash in get_hash_keys(map) {
+        let entries: Vec<ContentHash> = data().await.amazon.query(HASHES_TABLE_NAME, "hash", hash.clone()).await
+            .map_err(APIError::database_error)?;
+        // Backgrounds sharing one byte usually aren't similar at all
+        candidates.extend(entries
+            .into_iter()
+            .filter(|entry| !hash.starts_with("image_") || is_similar(map.image_hash, entry.image_hash))
+            .map(|entry| entry.map_id));
+    }
+    candidates.remove(&map.id);
+
+    // Updates are checked again, but each match is only flagged once
+    let flagged: Vec<DuplicateFlag> = data().await.amazon.query(FLAGS_TABLE_NAME, "map_id", map.id.to_string()).await
+        .map_err(APIError::database_error)?;
+    for id in candidates {
+        if flagged.iter().any(|flag| flag.original_id == id) {
+            continue;
+        }
+        let Some(original) = data().await.amazon.get_item::<BeatMap>(MAPS_TABLE_NAME, id.to_string()).await
+            .map_err(APIError::database_error)? else {
+            continue;
+        };
+        if original.charter_uid == map.charter_uid {
+            continue;
+        }
+        let Some(reason) = get_duplicate_reason(map, &original) else {
+            continue;
+        };
+        println!("Flagged {} as a possible reupload of {}: {reason}", map.id, original.id);
+        data().await.amazon
+            .upload(FLAGS_TABLE_NAME, &DuplicateFlag {
+                id: Uuid::new_v4(),
+                map_id: map.id,
+                original_id: original.id,
+                reason,
+                flag_date: DateTime::<Utc>::from(SystemTime::now()),
+            }, None::<&Vec<String>>)
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+/// Adds the map's hashes to the index, dropping the ones only its old version had.
+async fn index_hashes(map: &BeatMap, old: Option<&BeatMap>) -> Result<(), APIError> {
+    let hashes = get_hash_keys(map);
+    for hash in old.map_or(vec![], get_hash_keys) {
+        if !hashes.contains(&hash) {
+            data().await.amazon.remove(HASHES_TABLE_NAME, "id", format!("{hash}_{}", map.id)).await
+                .map_err(APIError::database_error)?;
+        }
+    }
+    for hash in hashes {
+        data().await.amazon
+            .upload(HASHES_TABLE_NAME, &ContentHash {
+                id: format!("{hash}_{}", map.id),
+                hash,
+                map_id: map.id,
+                image_hash: map.image_hash,
+            }, None::<&Vec<String>>)
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+/// Removes the map from the hash index, for when it's deleted.
+pub async fn remove_hashes(map: &BeatMap) -> Result<(), APIError> {
+    for hash in get_hash_keys(map) {
+        data().await.amazon.remove(HASHES_TABLE_NAME, "id", format!("{hash}_{}", map.id)).await
+            .map_err(APIError::database_error)?;
+    }
+    Ok(())
+}
+
+/// Adds every map uploaded before the hash index existed.
+pub async fn index_all_hashes(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    let maps: Vec<BeatMap> = data().await.amazon.scan(MAPS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    for map in &maps {
+        index_hashes(map, None).await?;
+    }
+    Ok(format!("Indexed {} maps", maps.len()).reply())
+}
+
+fn get_hash_keys(map: &BeatMap) -> Vec<String> {
+    map.chart_hashes.iter().map(|hash| format!("chart_{hash}"))
+        .chain(map.audio_hashes.iter().map(|hash| format!("audio_{hash}")))
+        .chain(map.image_hash.into_iter().flat_map(|hash| {
+            (0..IMAGE_BANDS).map(move |band| format!("image_{band}_{:02x}", hash >> (band * 8) & 0xff))
+        }))
+        .collect()
+}
+
+fn is_similar(first: Option<u64>, second: Option<u64>) -> bool {
+    match (first, second) {
+        (Some(first), Some(second)) => (first ^ second).count_ones() <= MAX_IMAGE_DISTANCE,
+        _ => false,
+    }
+}
+
+// The same song is often charted by several people, so shared audio alone isn't enough
+fn get_duplicate_reason(map: &BeatMap, original: &BeatMap) -> Option<String> {
+    if map.chart_hashes.iter().any(|hash| original.chart_hashes.contains(hash)) {
+        return Some("Identical chart".to_string());
+    }
+    if !is_similar(map.image_hash, original.image_hash) {
+        return None;
+    }
+    if map.audio_hashes.iter().any(|hash| original.audio_hashes.contains(hash)) {
+        return Some("Identical audio and matching background".to_string());
+    }
+    Some("Matching background".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_with(charts: &[&str], audio: &[&str], image_hash: Option<u64>) -> BeatMap {
+        BeatMap {
+            chart_hashes: charts.iter().map(|hash| hash.to_string()).collect(),
+            audio_hashes: audio.iter().map(|hash| hash.to_string()).collect(),
+            image_hash,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn identical_chart_is_a_duplicate() {
+        let map = map_with(&["a", "b"], &[], None);
+        let original = map_with(&["b"], &[], None);
+        assert_eq!(get_duplicate_reason(&map, &original).as_deref(), Some("Identical chart"));
+    }
+
+    #[test]
+    fn shared_audio_needs_a_similar_background() {
+        let map = map_with(&[], &["song"], Some(0b1111));
+        let same_song = map_with(&[], &["song"], Some(u64::MAX));
+        assert_eq!(get_duplicate_reason(&map, &same_song), None);
+
+        let similar = map_with(&[], &["song"], Some(0b1111 ^ 0b111111 << 10));
+        assert_eq!(get_duplicate_reason(&map, &similar).as_deref(), Some("Identical audio and matching background"));
+        let too_far = map_with(&[], &["song"], Some(0b1111 ^ 0b1111111 << 10));
+        assert_eq!(get_duplicate_reason(&map, &too_far), None);
+    }
+
+    #[test]
+    fn similar_background_alone_is_a_duplicate() {
+        let map = map_with(&[], &["song"], Some(0b1111));
+        let original = map_with(&[], &["other"], Some(0b1011));
+        assert_eq!(get_duplicate_reason(&map, &original).as_deref(), Some("Matching background"));
+    }
+
+    #[test]
+    fn similar_backgrounds_share_an_index_key() {
+        let hash = 0x0123_4567_89ab_cdef;
+        // The most spread out difference the distance allows, one bit in each of six bytes
+        let spread = (0..MAX_IMAGE_DISTANCE as u64).fold(hash, |hash, byte| hash ^ 1 << (byte * 8));
+        let first = get_hash_keys(&map_with(&[], &[], Some(hash)));
+        let second = get_hash_keys(&map_with(&[], &[], Some(spread)));
+        assert_eq!(first.len(), IMAGE_BANDS as usize);
+        assert!(first.iter().any(|key| second.contains(key)));
+    }
+
+    #[test]
+    fn missing_background_never_matches() {
+        let map = map_with(&[], &["song"], None);
+        let original = map_with(&[], &["song"], None);
+        assert_eq!(get_duplicate_reason(&map, &original), None);
+    }
+
+    #[test]
+    fn hash_keys_separate_charts_and_audio() {
+        let map = map_with(&["same"], &["same"], None);
+        assert_eq!(get_hash_keys(&map), vec!["chart_same", "audio_same"]);
+        let map = map_with(&[], &[], Some(0xff));
+        assert_eq!(get_hash_keys(&map)[..2], ["image_0_ff", "image_1_00"]);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::util::amazon::get_object_url;
+use crate::util::database::BeatMap;
+use crate::util::image::ImageSize;
+use crate::util::share::get_share_card_key;
+use crate::util::warp::get_map;
+use image::ImageFormat;
+use std::env;
+use urlencoding::encode;
+use warp::{reply, Rejection, Reply};
+
+// Used when the DOMAIN config value isn't set
+pub const DEFAULT_DOMAIN: &'static str = "beatblockbrowser.me";
+
+fn get_site_url() -> String {
+    format!("https://{}", env::var("DOMAIN").unwrap_or(DEFAULT_DOMAIN.to_string()))
+}
+
+/// Server-rendered so link previews get the map's OpenGraph and Twitter tags,
+/// browsers are sent on to the normal search page.
+pub async fn map_page(id: String) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    Ok(reply::html(render_map_page(&map)))
+}
+
+fn render_map_page(map: &BeatMap) -> String {
+    let title = escape(&format!("{} - {}", map.artist, map.song));
+    let difficulties = map
+        .difficulties
+        .iter()
+        .map(|variant| format!("{} {}", variant.display, variant.difficulty))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let description = escape(&format!("Charted by {}. {}", map.charter, difficulties));
+    let site_url = get_site_url();
+    let url = format!("{site_url}/map/{}", map.id);
+    let redirect = escape(&format!("{site_url}/search.html?query={}", encode(&format!("{} {}", map.charter, map.song))));
+    let image = if map.share_card {
+        Some(get_object_url(&get_share_card_key(&map.id)))
+    } else if map.image {
+        Some(get_object_url(&ImageSize::Full.get_key(&map.id, ImageFormat::Png)))
+    } else {
+        None
+    };
+    let image_tags = image.map_or(String::new(), |image| format!(
+        r#"<meta property="og:image" content="{image}">
+    <meta name="twitter:image" content="{image}">"#
+    ));
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>{title}</title>
+    <meta property="og:type" content="website">
+    <meta property="og:site_name" content="Beatblock Browser">
+    <meta property="og:url" content="{url}">
+    <meta property="og:title" content="{title}">
+    <meta property="og:description" content="{description}">
+    <meta name="twitter:card" content="summary_large_image">
+    <meta name="twitter:title" content="{title}">
+    <meta name="twitter:description" content="{description}">
+    {image_tags}
+    <meta http-equiv="refresh" content="0; url={redirect}">
+</head>
+<body>
+    <a href="{redirect}">{title}</a>
+</body>
+</html>"#
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
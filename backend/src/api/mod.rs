@@ -1,16 +1,32 @@
+use crate::api::bundle::{MAX_BUNDLE_MAPS, MAX_BUNDLE_SIZE};
+use crate::api::collections::MAX_COLLECTION_MAPS;
+use crate::api::comments::MAX_COMMENT_LENGTH;
+use crate::api::upload::MAX_PACK_LEVELS;
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::time::error::Elapsed;
 use warp::hyper::StatusCode;
 
+pub mod bundle;
+pub mod collections;
+pub mod comments;
 pub mod delete;
+pub mod details;
 pub mod downloaded;
+pub mod edit;
+pub mod feed;
+pub mod flags;
+pub mod mappage;
+pub mod notifications;
+pub mod ratings;
 pub mod search;
 pub mod upload;
 pub mod upvote;
 pub mod usersongs;
+pub mod versions;
 pub mod signin;
+pub mod stats;
 
 #[derive(Serialize, Deserialize)]
 pub struct AuthenticatedRequest {
@@ -30,6 +46,8 @@ pub enum APIError {
     SerdeError(#[from] serde_json::Error),
     #[error("Already upvoted!")]
     AlreadyUpvoted(),
+    #[error("Already voted on this map!")]
+    AlreadyVoted(),
     #[error("Already downloaded!")]
     AlreadyDownloaded(),
     #[error("Expected a multi-part form!")]
@@ -49,7 +67,21 @@ pub enum APIError {
     #[error("Served timed out reading archive")]
     TimeoutError(#[from] Elapsed),
     #[error("You do not have permission to perform this action")]
-    PermissionError()
+    PermissionError(),
+    #[error("Unknown map version")]
+    UnknownVersion(),
+    #[error("A new version must contain exactly one level")]
+    VersionLevelError(),
+    #[error("Packs can't have more than {} levels", MAX_PACK_LEVELS)]
+    PackSizeError(),
+    #[error("Bundles must contain between 1 and {} maps and be under {}MB", MAX_BUNDLE_MAPS, MAX_BUNDLE_SIZE / 1000000)]
+    BundleSizeError(),
+    #[error("Comments must be between 1 and {} characters", MAX_COMMENT_LENGTH)]
+    CommentLengthError(),
+    #[error("Already in the collection!")]
+    AlreadyInCollection(),
+    #[error("Collections can't have more than {} maps", MAX_COLLECTION_MAPS)]
+    CollectionSizeError()
 }
 
 impl APIError {
@@ -58,12 +90,20 @@ impl APIError {
             APIError::Ratelimited() => StatusCode::TOO_MANY_REQUESTS,
             APIError::AuthError(_)
             | APIError::AlreadyUpvoted()
+            | APIError::AlreadyVoted()
             | APIError::AlreadyDownloaded()
             | APIError::ArgumentError()
             | APIError::KnownArgumentError(_)
             | APIError::SongNameError(_)
             | APIError::ArchiveTypeError()
-            | APIError::PermissionError() => StatusCode::BAD_REQUEST,
+            | APIError::PermissionError()
+            | APIError::VersionLevelError()
+            | APIError::PackSizeError()
+            | APIError::BundleSizeError()
+            | APIError::CommentLengthError()
+            | APIError::AlreadyInCollection()
+            | APIError::CollectionSizeError() => StatusCode::BAD_REQUEST,
+            APIError::UnknownVersion() => StatusCode::NOT_FOUND,
             APIError::DatabaseError(_)
             | APIError::SerdeError(_)
             | APIError::ZipError(_)
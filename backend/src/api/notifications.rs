@@ -0,0 +1,215 @@
+use crate::api::downloaded::{get_downloaders, remove_downloads};
+use crate::api::feed::get_followers;
+use crate::api::APIError;
+use crate::util::amazon::{is_condition_failure, NOTIFICATIONS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{BeatMap, Notification, NotificationEvent, User, UserID};
+use crate::util::warp::Replyable;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+pub const NOTIFICATIONS_PAGE_SIZE: i32 = 25;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NotificationOptions {
+    // The `next` cursor of the page before
+    pub after: Option<String>,
+    // Only list unread notifications
+    #[serde(default)]
+    pub unread: bool,
+}
+
+#[derive(Serialize)]
+pub struct NotificationsResult {
+    pub results: Vec<Notification>,
+    pub unread: usize,
+    pub next: Option<String>,
+}
+
+/// The user's notifications, newest first.
+pub async fn notifications(user: Option<User>, options: NotificationOptions) -> Result<impl Reply, Rejection> {
+    let user = user.ok_or(APIError::AuthError("Missing token!".to_string()))?;
+    let (results, next) = data().await.amazon
+        .query_page(NOTIFICATIONS_TABLE_NAME, "user_id", user.id.to_string(),
+                    options.unread.then_some(("read", AttributeValue::Bool(false))), options.after, NOTIFICATIONS_PAGE_SIZE)
+        .await?;
+    let unread = data().await.amazon
+        .count(NOTIFICATIONS_TABLE_NAME, "user_id", user.id.to_string(), None, Some(("read", AttributeValue::Bool(false))))
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(NotificationsResult {
+        results,
+        unread,
+        next,
+    }.reply())
+}
+
+pub async fn read_notification(user: User, id: String) -> Result<impl Reply, Rejection> {
+    let notification: Notification = data().await.amazon
+        .get_item(NOTIFICATIONS_TABLE_NAME, id)
+        .await
+        .map_err(APIError::database_error)?
+        .filter(|notification: &Notification| notification.user_id == user.id)
+        .ok_or(APIError::ArgumentError())?;
+    mark_read(&notification).await?;
+    Ok("Ok".reply())
+}
+
+pub async fn read_notifications(user: User) -> Result<impl Reply, Rejection> {
+    let mut cursor = None;
+    loop {
+        let (notifications, next): (Vec<Notification>, _) = data().await.amazon
+            .query_page(NOTIFICATIONS_TABLE_NAME, "user_id", user.id.to_string(),
+                        Some(("read", AttributeValue::Bool(false))), cursor, NOTIFICATIONS_PAGE_SIZE)
+            .await?;
+        for notification in &notifications {
+            mark_read(notification).await?;
+        }
+        let Some(next) = next else {
+            break;
+        };
+        cursor = Some(next);
+    }
+    Ok("Ok".reply())
+}
+
+async fn mark_read(notification: &Notification) -> Result<(), APIError> {
+    data().await.amazon
+        .set_fields(NOTIFICATIONS_TABLE_NAME, notification.id.clone(), vec![("read", AttributeValue::Bool(true))])
+        .await
+        .map_err(APIError::database_error)
+}
+
+/// Moves a merged account's notifications over, except upvotes the other account now made on its own map.
+pub async fn reassign_notifications(from: &UserID, to: &UserID) -> Result<(), APIError> {
+    let mut cursor = None;
+    loop {
+        let (notifications, next): (Vec<Notification>, _) = data().await.amazon
+            .query_page(NOTIFICATIONS_TABLE_NAME, "user_id", from.to_string(), None, cursor, NOTIFICATIONS_PAGE_SIZE)
+            .await?;
+        for notification in notifications {
+            if notification.id == format!("upvote_{}_{to}", notification.map_id) {
+                data().await.amazon.remove(NOTIFICATIONS_TABLE_NAME, "id", notification.id)
+                    .await
+                    .map_err(APIError::database_error)?;
+                continue;
+            }
+            data().await.amazon
+                .set_fields(NOTIFICATIONS_TABLE_NAME, notification.id, vec![("user_id", AttributeValue::S(to.to_string()))])
+                .await
+                .map_err(APIError::database_error)?;
+        }
+        let Some(next) = next else {
+            return Ok(());
+        };
+        cursor = Some(next);
+    }
+}
+
+/// Notifies the users the event concerns in the background, so it never fails what caused it.
+/// The user who caused it isn't told about what they did themselves.
+pub fn schedule_notifications(map: BeatMap, event: NotificationEvent, actor: UserID) {
+    tokio::spawn(async move {
+        if let Err(err) = send_notifications(&map, event, &actor).await {
+            println!("Failed to send {event:?} notifications for {}: {err:?}", map.id);
+        }
+    });
+}
+
+async fn send_notifications(map: &BeatMap, event: NotificationEvent, actor: &UserID) -> Result<(), APIError> {
+    let date = DateTime::<Utc>::from(SystemTime::now());
+    let users = match event {
+        NotificationEvent::Upload => get_followers(&map.charter_uid).await?,
+        NotificationEvent::Update { .. } => get_downloaders(&map.id).await?,
+        NotificationEvent::Delete => {
+            let mut users = get_downloaders(&map.id).await?;
+            users.push(map.charter_uid);
+            users
+        }
+        NotificationEvent::Upvote => {
+            let Some(notification) = get_notifications(vec![map.charter_uid], map, event, actor, date).pop() else {
+                return Ok(());
+            };
+            // Voting on the same map again keeps the first notification
+            return data().await.amazon
+                .upload_if(NOTIFICATIONS_TABLE_NAME, &notification, "attribute_not_exists(id)", vec![])
+                .await
+                .or_else(|err| match is_condition_failure(&err) {
+                    true => Ok(()),
+                    false => Err(APIError::database_error(err)),
+                });
+        }
+    };
+    let notifications = get_notifications(users, map, event, actor, date);
+    data().await.amazon.upload_all(NOTIFICATIONS_TABLE_NAME, &notifications)
+        .await
+        .map_err(APIError::database_error)?;
+    // The deleted map's downloads were only kept to tell its downloaders
+    if let NotificationEvent::Delete = event {
+        remove_downloads(&map.id).await?;
+    }
+    Ok(())
+}
+
+fn get_notifications(mut users: Vec<UserID>, map: &BeatMap, event: NotificationEvent, actor: &UserID,
+                     date: DateTime<Utc>) -> Vec<Notification> {
+    users.sort();
+    users.dedup();
+    users.into_iter()
+        .filter(|user| user != actor)
+        .map(|user| Notification {
+            id: match event {
+                NotificationEvent::Upvote => format!("upvote_{}_{actor}", map.id),
+                _ => Uuid::new_v4().to_string(),
+            },
+            user_id: user,
+            map_id: map.id,
+            song: map.song.clone(),
+            event,
+            date,
+            read: false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actor_and_repeats_are_left_out() {
+        let map = BeatMap { id: Uuid::new_v4(), song: "Song".to_string(), ..Default::default() };
+        let (actor, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let notifications = get_notifications(vec![user, actor, user], &map, NotificationEvent::Delete, &actor, Utc::now());
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].user_id, user);
+        assert_eq!(notifications[0].song, "Song");
+        assert!(!notifications[0].read);
+    }
+
+    #[test]
+    fn upvotes_are_notified_once_per_voter() {
+        let map = BeatMap { id: Uuid::new_v4(), charter_uid: Uuid::new_v4(), ..Default::default() };
+        let voter = Uuid::new_v4();
+        let get_id = |voter: &UserID| get_notifications(vec![map.charter_uid], &map, NotificationEvent::Upvote, voter, Utc::now())
+            .pop()
+            .map(|notification| notification.id);
+        assert_eq!(get_id(&voter), get_id(&voter));
+        assert_ne!(get_id(&voter), get_id(&Uuid::new_v4()));
+        // Charters upvoting their own maps aren't notified
+        assert_eq!(get_id(&map.charter_uid), None);
+    }
+
+    #[test]
+    fn other_events_get_new_ids() {
+        let map = BeatMap { id: Uuid::new_v4(), ..Default::default() };
+        let (actor, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let first = get_notifications(vec![user], &map, NotificationEvent::Update { version: 2 }, &actor, Utc::now());
+        let second = get_notifications(vec![user], &map, NotificationEvent::Update { version: 2 }, &actor, Utc::now());
+        assert_ne!(first[0].id, second[0].id);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::api::APIError;
+use crate::util::amazon::{is_condition_failure, to_attribute, MAPS_TABLE_NAME, RATINGS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{BeatMap, Rating, UserID};
+use crate::util::warp::{get_map, get_user, Replyable};
+use anyhow::Error;
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use warp::{Rejection, Reply};
+
+pub const MAX_RATING: u8 = 5;
+// A bit past the hardest declared difficulties, so Apocrypha maps can still be voted harder
+pub const MAX_DIFFICULTY_VOTE: f64 = 30.0;
+
+#[derive(Debug, Deserialize)]
+pub struct RatingRequest {
+    token: String,
+    rating: Option<u8>,
+    // Perceived difficulty by variant display name
+    #[serde(default)]
+    difficulties: HashMap<String, f64>,
+}
+
+/// Rates the map and votes on its variants' difficulties, anything left out keeps the user's earlier vote.
+pub async fn rate(id: String, request: RatingRequest) -> Result<impl Reply, Rejection> {
+    let user = get_user(request.token).await?;
+    let map = get_map(id).await?;
+    if map.charter_uid == user.id {
+        return Err(APIError::PermissionError().into());
+    }
+    if request.rating.is_some_and(|rating| rating == 0 || rating > MAX_RATING)
+        || request.difficulties.iter().any(|(display, difficulty)| {
+            !(0.0..=MAX_DIFFICULTY_VOTE).contains(difficulty)
+                || !map.difficulties.iter().any(|variant| &variant.display == display)
+        })
+    {
+        return Err(APIError::ArgumentError().into());
+    }
+
+    let id = format!("{}_{}", map.id, user.id);
+    let stored: Option<Rating> = data().await.amazon
+        .get_item(RATINGS_TABLE_NAME, id.clone())
+        .await
+        .map_err(APIError::database_error)?;
+    let mut difficulties = stored.as_ref().map(|stored| stored.difficulties.clone()).unwrap_or_default();
+    difficulties.extend(request.difficulties);
+    let rating = Rating {
+        id,
+        map_id: map.id,
+        user_id: user.id,
+        rating: request.rating.or(stored.and_then(|stored| stored.rating)),
+        difficulties,
+        rating_date: DateTime::<Utc>::from(SystemTime::now()),
+    };
+    // The totals take back whatever this replaced, even if it changed since it was read
+    let replaced: Option<Rating> = data().await.amazon
+        .replace(RATINGS_TABLE_NAME, &rating)
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(update_aggregates(map, replaced.as_ref(), &rating).await?.reply())
+}
+
+/// Moves a merged account's ratings over. Where both accounts rated the map, or it's now the
+/// account's own map, the moved rating is dropped and taken out of the totals.
+pub async fn reassign_ratings(from: &UserID, to: &UserID) -> Result<(), APIError> {
+    let ratings: Vec<Rating> = data().await.amazon
+        .query(RATINGS_TABLE_NAME, "user_id", from.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    for rating in ratings {
+        let map: Option<BeatMap> = data().await.amazon.get_item(MAPS_TABLE_NAME, rating.map_id.to_string())
+            .await
+            .map_err(APIError::database_error)?;
+        let moved = Rating {
+            id: format!("{}_{to}", rating.map_id),
+            user_id: *to,
+            map_id: rating.map_id,
+            rating: rating.rating,
+            difficulties: rating.difficulties.clone(),
+            rating_date: rating.rating_date,
+        };
+        let kept = match &map {
+            Some(map) if map.charter_uid == *to => false,
+            _ => data().await.amazon
+                .upload_if(RATINGS_TABLE_NAME, &moved, "attribute_not_exists(id)", vec![])
+                .await
+                .map(|_| true)
+                .or_else(|err| match is_condition_failure(&err) {
+                    true => Ok(false),
+                    false => Err(APIError::database_error(err)),
+                })?,
+        };
+        data().await.amazon.remove(RATINGS_TABLE_NAME, "id", rating.id.clone())
+            .await
+            .map_err(APIError::database_error)?;
+        if let (Some(map), false) = (map, kept) {
+            let removed = Rating { rating: None, difficulties: HashMap::new(), ..moved };
+            update_aggregates(map, Some(&rating), &removed).await?;
+        }
+    }
+    Ok(())
+}
+
+// Totals change by the difference between the old and new votes in one update, so concurrent ratings
+// can't overwrite each other. The variants are matched by position, so a re-upload that moved them
+// fails the update and it's retried on the new variants.
+async fn update_aggregates(mut map: BeatMap, old: Option<&Rating>, new: &Rating) -> Result<BeatMap, APIError> {
+    loop {
+        match add_votes(&map, old, new).await {
+            Ok(updated) => return set_averages(updated).await,
+            Err(err) if is_condition_failure(&err) => {
+                map = data().await.amazon.get_item(MAPS_TABLE_NAME, map.id.to_string())
+                    .await
+                    .map_err(APIError::database_error)?
+                    .ok_or(APIError::ArgumentError())?;
+            }
+            Err(err) => return Err(APIError::database_error(err)),
+        }
+    }
+}
+
+async fn add_votes(map: &BeatMap, old: Option<&Rating>, new: &Rating) -> Result<BeatMap, Error> {
+    let stars = |rating: Option<&Rating>| rating.and_then(|rating| rating.rating).map(i64::from);
+    let (old_stars, new_stars) = (stars(old), stars(Some(new)));
+    let mut sets = vec![
+        "rating_total = if_not_exists(rating_total, :initial_total) + :stars".to_string(),
+        "#ratings = if_not_exists(#ratings, :zero) + :count".to_string(),
+    ];
+    let mut conditions = vec!["attribute_exists(id)".to_string()];
+    let mut values = vec![
+        // Maps rated before totals were kept start from their average
+        (":initial_total".to_string(), number((map.rating.unwrap_or_default() * map.ratings as f64).round())),
+        (":stars".to_string(), number(new_stars.unwrap_or_default() - old_stars.unwrap_or_default())),
+        (":count".to_string(), number(new_stars.is_some() as i64 - old_stars.is_some() as i64)),
+        (":zero".to_string(), number(0)),
+        (":one".to_string(), number(1)),
+    ];
+    for (i, variant) in map.difficulties.iter().enumerate() {
+        let old_vote = old.and_then(|old| old.difficulties.get(&variant.display)).copied();
+        let new_vote = new.difficulties.get(&variant.display).copied();
+        if old_vote == new_vote {
+            continue;
+        }
+        let path = format!("difficulties[{i}]");
+        sets.push(format!("{path}.difficulty_total = if_not_exists({path}.difficulty_total, :initial_{i}) + :total_{i}"));
+        sets.push(format!("{path}.difficulty_votes = if_not_exists({path}.difficulty_votes, :zero) + :votes_{i}"));
+        conditions.push(format!("{path}.#display = :display_{i}"));
+        let initial = variant.community_difficulty.unwrap_or_default() * variant.difficulty_votes as f64;
+        values.push((format!(":initial_{i}"), number(initial)));
+        values.push((format!(":total_{i}"), number(new_vote.unwrap_or_default() - old_vote.unwrap_or_default())));
+        values.push((format!(":votes_{i}"), number(new_vote.is_some() as i64 - old_vote.is_some() as i64)));
+        values.push((format!(":display_{i}"), AttributeValue::S(variant.display.clone())));
+    }
+
+    let item = data().await.amazon
+        .update_returning(MAPS_TABLE_NAME, map.id.to_string(), |builder| {
+            values.iter().fold(
+                builder
+                    .update_expression(format!("SET {} ADD rating_version :one", sets.join(", ")))
+                    .condition_expression(conditions.join(" AND "))
+                    .expression_attribute_names("#ratings", "ratings")
+                    .expression_attribute_names("#display", "display"),
+                |builder, (name, value)| builder.expression_attribute_values(name, value.clone()),
+            )
+        })
+        .await?;
+    Ok(serde_dynamo::from_item(item)?)
+}
+
+// DynamoDB can't divide, so averages are set from the totals afterwards. The write only goes through
+// if nothing changed them since, otherwise the newer rating sets them instead.
+async fn set_averages(mut map: BeatMap) -> Result<BeatMap, APIError> {
+    map.rating = get_average(map.rating_total as f64, map.ratings);
+    for variant in &mut map.difficulties {
+        variant.community_difficulty = get_average(variant.difficulty_total, variant.difficulty_votes);
+    }
+    data().await.amazon
+        .set_fields_if(MAPS_TABLE_NAME, map.id.to_string(), vec![
+            ("rating", to_attribute(&map.rating)?),
+            ("difficulties", to_attribute(&map.difficulties)?),
+        ], "rating_version = :seen", vec![(":seen", number(map.rating_version))])
+        .await
+        .or_else(|err| match is_condition_failure(&err) {
+            true => Ok(()),
+            false => Err(APIError::database_error(err)),
+        })?;
+    Ok(map)
+}
+
+fn get_average(total: f64, count: u64) -> Option<f64> {
+    (count > 0).then(|| total / count as f64)
+}
+
+fn number(value: impl ToString) -> AttributeValue {
+    AttributeValue::N(value.to_string())
+}
@@ -13,12 +13,28 @@ pub struct SearchResult {
     pub results: Vec<BeatMap>,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchOptions {
+    #[serde(default)]
+    pub sort: SortKey,
+}
+
+// How maps matching the query equally well are ordered
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Upvotes,
+    Score,
+}
+
 pub async fn search(
-    query: String
+    query: String,
+    options: SearchOptions,
 ) -> Result<impl Reply, Rejection> {
     let query = decode(query.deref()).map_err(|_| APIError::ArgumentError())?.to_string();
     Ok(SearchResult {
         query: query.clone(),
-        results: data().await.amazon.search_songs(&query).await.map_err(APIError::database_error)?,
+        results: data().await.amazon.search_songs(&query, options.sort).await.map_err(APIError::database_error)?,
     }.reply())
 }
\ No newline at end of file
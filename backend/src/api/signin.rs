@@ -1,6 +1,12 @@
+use crate::api::collections::reassign_collections;
+use crate::api::comments::reassign_comments;
+use crate::api::downloaded::reassign_downloads;
+use crate::api::feed::{reassign_feed, reassign_follows};
+use crate::api::notifications::reassign_notifications;
+use crate::api::ratings::reassign_ratings;
 use crate::api::APIError;
 use crate::util::amazon::{MAPS_TABLE_NAME, TOKENS_TABLE_NAME, USERS_TABLE_NAME};
-use crate::util::database::{AccountLink, User, UserID};
+use crate::util::database::{AccountLink, MapID, User, UserID};
 use crate::util::warp::Replyable;
 use crate::util::{data, get_user_from_link};
 use anyhow::Error;
@@ -15,7 +21,7 @@ use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
 use std::env;
 use warp::{Rejection, Reply};
-use crate::api::upvote::unvote_for_map;
+use crate::api::upvote::{remove_vote, Vote};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DiscordTokenRequest {
@@ -73,6 +79,8 @@ pub async fn google_sync(user: User, code: String) -> Result<impl Reply, Rejecti
 }
 
 pub async fn merge(mut first: User, second: User) -> Result<(), APIError> {
+    reassign_follows(&second, &first).await?;
+    reassign_feed(&second.id, &first.id).await?;
     for map in second.maps {
         data()
             .await
@@ -88,13 +96,40 @@ pub async fn merge(mut first: User, second: User) -> Result<(), APIError> {
             .await
             .map_err(APIError::database_error)?;
     }
-    first.downloaded.extend(second.downloaded);
-    for unvoting in first.upvoted.clone().iter().filter(|map| second.upvoted.contains(map)) {
-        let unvoting = data().await.amazon.query_one(MAPS_TABLE_NAME, "id", unvoting.to_string()).await.map_err(APIError::database_error)?
-            .ok_or(APIError::DatabaseError(Error::msg("Failed to find map while merging!")))?;
-        unvote_for_map(&unvoting, &mut first).await?;
+    reassign_comments(&second.id, &first.id).await?;
+    reassign_collections(&second.id, &first.id).await?;
+    reassign_downloads(&second.id, &first.id, &second.downloaded).await?;
+    reassign_ratings(&second.id, &first.id).await?;
+    reassign_notifications(&second.id, &first.id).await?;
+    for map in second.downloaded {
+        if !first.downloaded.contains(&map) {
+            first.downloaded.push(map);
+        }
+    }
+    for charter in second.following {
+        if !first.following.contains(&charter) {
+            first.following.push(charter);
+        }
+    }
+    // Neither account follows the merged one
+    let merged = [first.id, second.id];
+    first.following.retain(|charter| !merged.contains(charter));
+    first.feed_read = first.feed_read.max(second.feed_read);
+    // Maps both accounts voted on keep the second account's vote, so each map is only counted once
+    for vote in [Vote::Up, Vote::Down] {
+        let removing: Vec<MapID> = vote.get_votes(&mut first)
+            .iter()
+            .filter(|map| second.upvoted.contains(map) || second.downvoted.contains(map))
+            .copied()
+            .collect();
+        for map in removing {
+            let map = data().await.amazon.query_one(MAPS_TABLE_NAME, "id", map.to_string()).await.map_err(APIError::database_error)?
+                .ok_or(APIError::DatabaseError(Error::msg("Failed to find map while merging!")))?;
+            remove_vote(&map, &mut first, vote).await?;
+        }
     }
     first.upvoted.extend(second.upvoted);
+    first.downvoted.extend(second.downvoted);
     data()
         .await
         .amazon
@@ -0,0 +1,111 @@
+use crate::api::APIError;
+use crate::util::amazon::{MAPS_TABLE_NAME, STATS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{BeatMap, DailyStats, MapID};
+use crate::util::warp::{get_map, Replyable};
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use warp::{Rejection, Reply};
+
+#[derive(Serialize)]
+pub struct StatsResult {
+    pub downloads: u64,
+    pub upvotes: u64,
+    // Oldest first, days without any activity are left out
+    pub days: Vec<DayStats>,
+}
+
+#[derive(Default, Serialize)]
+pub struct DayStats {
+    pub day: NaiveDate,
+    pub downloads: u64,
+    pub upvotes: i64,
+    pub downvotes: i64,
+}
+
+#[derive(Serialize)]
+pub struct CharterStatsResult {
+    #[serde(flatten)]
+    pub total: StatsResult,
+    pub maps: Vec<MapStats>,
+}
+
+#[derive(Serialize)]
+pub struct MapStats {
+    pub id: MapID,
+    pub song: String,
+    pub downloads: u64,
+    pub upvotes: u64,
+}
+
+pub async fn map_stats(id: String) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    let stats = data().await.amazon.query(STATS_TABLE_NAME, "map_id", map.id.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(StatsResult {
+        downloads: map.downloads,
+        upvotes: map.upvotes,
+        days: sum_days(stats),
+    }.reply())
+}
+
+/// Stats summed over every map the charter uploaded.
+pub async fn charter_stats(charter: String) -> Result<impl Reply, Rejection> {
+    let mut maps: Vec<BeatMap> = data().await.amazon.query(MAPS_TABLE_NAME, "charter_uid", charter.clone())
+        .await
+        .map_err(APIError::database_error)?;
+    maps.sort_by(|first, second| first.downloads.cmp(&second.downloads).reverse());
+    let stats = data().await.amazon.query(STATS_TABLE_NAME, "charter_uid", charter)
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(CharterStatsResult {
+        total: StatsResult {
+            downloads: maps.iter().map(|map| map.downloads).sum(),
+            upvotes: maps.iter().map(|map| map.upvotes).sum(),
+            days: sum_days(stats),
+        },
+        maps: maps.into_iter().map(|map| MapStats {
+            id: map.id,
+            song: map.song,
+            downloads: map.downloads,
+            upvotes: map.upvotes,
+        }).collect(),
+    }.reply())
+}
+
+fn sum_days(stats: Vec<DailyStats>) -> Vec<DayStats> {
+    let mut days: BTreeMap<NaiveDate, DayStats> = BTreeMap::new();
+    for stat in stats {
+        let day = days.entry(stat.day).or_insert_with(|| DayStats {
+            day: stat.day,
+            ..Default::default()
+        });
+        day.downloads += stat.downloads;
+        day.upvotes += stat.upvotes;
+        day.downvotes += stat.downvotes;
+    }
+    days.into_values().collect()
+}
+
+/// Adds to today's bucket for the map, `field` is downloads, upvotes or downvotes.
+pub async fn record_stat(map: &BeatMap, field: &str, amount: i64) -> Result<(), APIError> {
+    let day = Utc::now().date_naive();
+    data().await.amazon
+        .update(STATS_TABLE_NAME, format!("{}_{day}", map.id), |builder| {
+            builder
+                .update_expression("SET map_id = :map, charter_uid = :charter, #day = :day, \
+                    #field = if_not_exists(#field, :start) + :amount")
+                .expression_attribute_names("#day", "day")
+                .expression_attribute_names("#field", field)
+                .expression_attribute_values(":map", AttributeValue::S(map.id.to_string()))
+                .expression_attribute_values(":charter", AttributeValue::S(map.charter_uid.to_string()))
+                .expression_attribute_values(":day", AttributeValue::S(day.to_string()))
+                .expression_attribute_values(":start", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":amount", AttributeValue::N(amount.to_string()))
+        })
+        .await
+        .map_err(APIError::database_error)
+}
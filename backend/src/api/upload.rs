@@ -1,24 +1,34 @@
 use crate::api::APIError;
-use crate::parsing::{check_archive, get_parser, parse_archive, BackgroundData};
-use crate::util::amazon::{MAPS_TABLE_NAME, USERS_TABLE_NAME};
-use crate::util::database::{BeatMap, UserID};
-use crate::util::image::save_image;
+use crate::api::feed::publish;
+use crate::api::flags::check_duplicates;
+use crate::api::notifications::schedule_notifications;
+use crate::parsing::diff::{diff_archives, DiffSummary};
+use crate::parsing::{get_content_hashes, get_parser, parse_archive, BackgroundData, FileData};
+use crate::util::amazon::{is_condition_failure, to_attribute, MAPS_TABLE_NAME, USERS_TABLE_NAME};
+use crate::util::database::{get_diff_key, get_timeline_key, BeatMap, MapVersion, NotificationEvent, UserID};
+use crate::util::image::{get_image_hash, get_palette, read_image, render_image, save_image, ImageSize, OUTPUT_FORMATS};
+use crate::util::share::{get_share_card_key, render_share_card};
 use crate::util::ratelimiter::{SiteAction, UniqueIdentifier};
 use crate::util::warp::{get_user, Replyable};
-use crate::util::{data, LockResultExt};
+use crate::util::archive::acquire_archive;
+use crate::util::{data, get_search_combos, schedule_pack_update, LockResultExt};
 use aws_sdk_dynamodb::types::AttributeValue;
 use bytes::BufMut;
-use chrono::{DateTime, Utc};
+use chrono::DateTime;
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::ops::{Deref, DerefMut};
 use std::time::{Duration, SystemTime};
 use tokio::time::timeout;
+use urlencoding::encode;
 use uuid::Uuid;
 use warp::multipart::FormData;
 use warp::{Rejection, Reply};
 
 pub const MAX_SIZE: u32 = 200000000;
+// Every level becomes its own map under one rate limit check, and all of them have to fit in the timeout
+pub const MAX_PACK_LEVELS: usize = 20;
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct UploadForm {
@@ -27,8 +37,8 @@ pub struct UploadForm {
     beatmap: Vec<u8>,
 }
 
-pub async fn upload(identifier: UniqueIdentifier, form: FormData) -> Result<impl Reply, Rejection> {
-    let form: Vec<(String, Vec<u8>)> = form.and_then(|mut field| async move {
+pub async fn read_form(form: FormData) -> Result<Vec<(String, Vec<u8>)>, APIError> {
+    form.and_then(|mut field| async move {
         let mut buffer = Vec::new();
         while let Some(data) = field.data().await {
             buffer.put(data?);
@@ -36,7 +46,11 @@ pub async fn upload(identifier: UniqueIdentifier, form: FormData) -> Result<impl
         Ok((field.name().to_string(), buffer))
     })
         .try_collect()
-        .await.map_err(|_| APIError::ArgumentError())?;
+        .await.map_err(|_| APIError::ArgumentError())
+}
+
+pub async fn upload(identifier: UniqueIdentifier, form: FormData) -> Result<impl Reply, Rejection> {
+    let form = read_form(form).await?;
     
     let mut beatmap = None;
     let mut token = None;
@@ -49,7 +63,7 @@ pub async fn upload(identifier: UniqueIdentifier, form: FormData) -> Result<impl
     }
     
     let user = get_user(String::from_utf8_lossy(token.ok_or(APIError::ArgumentError())?.deref()).to_string()).await?;
-    let map = timeout(
+    let upload = timeout(
         Duration::from_millis(10000),
         upload_beatmap(
             beatmap.ok_or(APIError::ArgumentError())?,
@@ -59,51 +73,182 @@ pub async fn upload(identifier: UniqueIdentifier, form: FormData) -> Result<impl
     )
     .await.map_err(|err| APIError::TimeoutError(err))??;
     
-    Ok(format!("query={} {}", map.charter, map.song).reply())
+    let mut response = format!("query={}", get_search_query(&upload.maps));
+    for warning in &upload.warnings {
+        response += &format!("&warning={}", encode(warning));
+    }
+    Ok(response.reply())
 }
 
+pub struct LevelUpload {
+    pub beatmap: BeatMap,
+    pub archive: Vec<u8>,
+    pub image: Option<Vec<u8>>,
+    pub bg_data: Option<BackgroundData>,
+    pub timelines: Vec<Option<Vec<u8>>>,
+}
+
+pub struct UploadResult {
+    pub maps: Vec<BeatMap>,
+    // Problems that didn't stop the upload, shown to the uploader
+    pub warnings: Vec<String>,
+}
+
+/// Uploads every level in the archive, levels uploaded together are grouped into a pack.
 pub async fn upload_beatmap(
     mut beatmap_data: Vec<u8>,
     ip: UniqueIdentifier,
     charter_id: UserID,
-) -> Result<BeatMap, APIError> {
-    let (mut beatmap, image, bg_data) = create_beatmap(&mut beatmap_data, charter_id)?;
+) -> Result<UploadResult, APIError> {
+    let levels = create_beatmaps(&mut beatmap_data, charter_id)?;
 
-    // Save the beatmap
-    if let Some(map) = data().await
+    let mut charter_maps: Vec<BeatMap> = data().await
         .amazon
         .query(MAPS_TABLE_NAME, "charter_uid", charter_id.to_string())
         .await
-        .map_err(APIError::database_error)?
+        .map_err(APIError::database_error)?;
+    let levels: Vec<(LevelUpload, Option<BeatMap>)> = levels
         .into_iter()
-        .filter(|map: &BeatMap| map.song == beatmap.song)
-        .next()
-    {
+        .map(|level| {
+            let existing = charter_maps
+                .iter()
+                .position(|map| map.song == level.beatmap.song)
+                .map(|i| charter_maps.swap_remove(i));
+            (level, existing)
+        })
+        .collect();
+    if levels.iter().any(|(_, existing)| existing.is_none()) {
+        data().await.ratelimiter
+            .lock()
+            .ignore_poison()
+            .check_limited(SiteAction::Upload, &ip)?;
+    }
+
+    // Re-uploading a pack keeps its old id, and updating one of its levels on its own keeps it in the pack
+    let pack_id = match levels.as_slice() {
+        [(_, existing)] => existing.as_ref().and_then(|existing| existing.pack_id),
+        levels => Some(levels
+            .iter()
+            .find_map(|(_, existing)| existing.as_ref()?.pack_id)
+            .unwrap_or_else(Uuid::new_v4)),
+    };
+    let mut changed_packs: Vec<Uuid> = pack_id.into_iter().collect();
+    let mut maps = vec![];
+    let mut warnings = vec![];
+    for (level, existing) in levels {
+        if let Some(old_pack) = existing.as_ref().and_then(|existing| existing.pack_id) {
+            if !changed_packs.contains(&old_pack) {
+                changed_packs.push(old_pack);
+            }
+        }
+        maps.push(upload_level(level, existing, pack_id, String::new(), &mut warnings).await?);
+    }
+    for pack in changed_packs {
+        schedule_pack_update(pack);
+    }
+    Ok(UploadResult { maps, warnings })
+}
+
+pub async fn upload_level(
+    level: LevelUpload,
+    existing: Option<BeatMap>,
+    pack_id: Option<Uuid>,
+    changelog: String,
+    warnings: &mut Vec<String>,
+) -> Result<BeatMap, APIError> {
+    let LevelUpload { mut beatmap, archive, image, bg_data, timelines } = level;
+    beatmap.archive_hash = Some(format!("{:x}", Sha256::digest(&archive)));
+    beatmap.pack_id = pack_id;
+    if let Some(map) = &existing {
         // Update the old map instead
         beatmap.id = map.id;
+        beatmap.upvotes = map.upvotes;
+        carry_ratings(&mut beatmap, map);
+    }
+    let changes = match &existing {
+        Some(old) if old.archive_hash != beatmap.archive_hash => save_diff(old, &archive).await,
+        _ => None,
+    };
+    beatmap.versions = get_versions(existing.as_ref(), &beatmap, changelog, changes);
+
+    let decoded = read_image(&image, &bg_data).unwrap_or_else(|warning| {
+        warnings.push(format!("{}: {warning}", beatmap.song));
+        None
+    });
+    // Hashed before recoloring, so a recolored reupload still matches
+    beatmap.image_hash = decoded.as_ref().map(get_image_hash);
+    let background = decoded.map(|decoded| render_image(&decoded, &bg_data));
+    if let Some(background) = &background {
+        beatmap.image_sizes = save_image(background, &beatmap.id).await?;
+        beatmap.palette = get_palette(background);
+    }
+    beatmap.image = !beatmap.image_sizes.is_empty();
+
+    let share_card = render_share_card(&beatmap, background.as_ref()).unwrap_or_else(|err| {
+        println!("Failed to render share card for {}: {err:?}", beatmap.id);
+        None
+    });
+    beatmap.share_card = share_card.is_some();
+    if let Some(share_card) = share_card {
         data().await.amazon
-            .update(MAPS_TABLE_NAME, beatmap.id.to_string(), |builder| {
-                builder
-                    .update_expression("SET upload_date = :date")
-                    .expression_attribute_values(
-                        ":date",
-                        AttributeValue::S(<DateTime<Utc> as ToString>::to_string(&DateTime::from(
-                            SystemTime::now(),
-                        ))),
-                    )
-            })
+            .upload_object(share_card, get_share_card_key(&beatmap.id).as_str())
             .await
             .map_err(APIError::database_error)?;
-    } else {
-        data().await.ratelimiter
-            .lock()
-            .ignore_poison()
-            .check_limited(SiteAction::Upload, &ip)?;
+    }
+
+    // Archives are stored by hash, so identical uploads share one object. It's stored before the map
+    // points to it, and older versions of this map already count for their archives.
+    let hash = beatmap.archive_hash.clone().unwrap_or_default();
+    let acquired = match existing.as_ref().is_some_and(|map| map.get_archive_hashes().contains(&hash)) {
+        true => None,
+        false => Some(acquire_archive(&hash, archive).await?),
+    };
 
+    // Save the beatmap
+    if let Some(mut old) = existing.clone() {
+        // Ratings made since the old map was read are carried over again
+        loop {
+            let saved = data().await.amazon
+                .set_fields_if(MAPS_TABLE_NAME, beatmap.id.to_string(), vec![
+                    ("song", to_attribute(&beatmap.song)?),
+                    ("artist", to_attribute(&beatmap.artist)?),
+                    ("charter", to_attribute(&beatmap.charter)?),
+                    ("difficulties", to_attribute(&beatmap.difficulties)?),
+                    ("description", to_attribute(&beatmap.description)?),
+                    ("artist_list", to_attribute(&beatmap.artist_list)?),
+                    ("title_prefix", to_attribute(&get_search_combos(&beatmap))?),
+                    ("upload_date", to_attribute(&beatmap.upload_date)?),
+                    ("update_date", to_attribute(&beatmap.update_date)?),
+                    ("image", AttributeValue::Bool(beatmap.image)),
+                    ("image_sizes", to_attribute(&beatmap.image_sizes)?),
+                    ("share_card", AttributeValue::Bool(beatmap.share_card)),
+                    ("palette", to_attribute(&beatmap.palette)?),
+                    ("image_hash", to_attribute(&beatmap.image_hash)?),
+                    ("chart_hashes", to_attribute(&beatmap.chart_hashes)?),
+                    ("audio_hashes", to_attribute(&beatmap.audio_hashes)?),
+                    ("archive_hash", to_attribute(&beatmap.archive_hash)?),
+                    ("pack_id", to_attribute(&beatmap.pack_id)?),
+                    ("versions", to_attribute(&beatmap.versions)?),
+                    ("rating_version", AttributeValue::N(beatmap.rating_version.to_string())),
+                ], "attribute_not_exists(rating_version) OR rating_version = :seen",
+                vec![(":seen", AttributeValue::N(old.rating_version.to_string()))])
+                .await;
+            match saved {
+                Err(err) if is_condition_failure(&err) => {
+                    old = data().await.amazon.get_item(MAPS_TABLE_NAME, beatmap.id.to_string())
+                        .await
+                        .map_err(APIError::database_error)?
+                        .ok_or(APIError::ArgumentError())?;
+                    carry_ratings(&mut beatmap, &old);
+                }
+                saved => break saved.map_err(APIError::database_error)?,
+            }
+        }
+    } else {
         data().await.amazon
             .add_to_list(
                 USERS_TABLE_NAME,
-                charter_id.to_string(),
+                beatmap.charter_uid.to_string(),
                 "maps",
                 beatmap.id.to_string(),
             )
@@ -113,41 +258,198 @@ pub async fn upload_beatmap(
             .await
             .map_err(APIError::database_error)?;
     }
+    // Released again if anything before this failed or timed out
+    if let Some(acquired) = acquired {
+        acquired.keep();
+    }
+    // Flags are only for admins to review, so they never stop the upload
+    if let Err(err) = check_duplicates(&beatmap, existing.as_ref()).await {
+        println!("Failed to check {} for duplicates: {err:?}", beatmap.id);
+    }
+
+    for (i, timeline) in timelines.into_iter().enumerate() {
+        let Some(timeline) = timeline else {
+            continue;
+        };
+        data().await.amazon
+            .upload_object(timeline, get_timeline_key(&beatmap.id, i).as_str())
+            .await
+            .map_err(APIError::database_error)?;
+    }
+    if let Some(old) = &existing {
+        remove_stale_images(old, &beatmap).await;
+        remove_stale_timelines(old, &beatmap).await;
+    }
 
-    save_image(&image, &bg_data, &&beatmap.id).await?;
-    data().await.amazon
-        .upload_object(beatmap_data, format!("{}.zip", beatmap.id).as_str())
-        .await
-        .map_err(APIError::database_error)?;
+    // Followers' feeds are filled in the background, so they never fail the upload
+    if existing.as_ref().is_none_or(|old| old.archive_hash != beatmap.archive_hash) {
+        let map = beatmap.clone();
+        tokio::spawn(async move {
+            if let Err(err) = publish(&map).await {
+                println!("Failed to add {} to feeds: {err:?}", map.id);
+            }
+        });
+    }
+    match &existing {
+        None => schedule_notifications(beatmap.clone(), NotificationEvent::Upload, beatmap.charter_uid),
+        Some(old) if old.archive_hash != beatmap.archive_hash => {
+            let version = beatmap.versions.last().map_or(1, |version| version.version);
+            schedule_notifications(beatmap.clone(), NotificationEvent::Update { version }, beatmap.charter_uid)
+        }
+        _ => {}
+    }
     Ok(beatmap)
 }
 
-pub fn create_beatmap(
+// Sizes the new background doesn't need, like when it's smaller or gone, would otherwise be kept forever
+async fn remove_stale_images(old: &BeatMap, beatmap: &BeatMap) {
+    // Maps from before sizes only have the full image
+    let old_sizes = match old.image_sizes.is_empty() && old.image {
+        true => vec![ImageSize::Full],
+        false => old.image_sizes.clone(),
+    };
+    for size in old_sizes.into_iter().filter(|size| !beatmap.image_sizes.contains(size)) {
+        for format in OUTPUT_FORMATS {
+            let key = size.get_key(&old.id, format);
+            if let Err(err) = data().await.amazon.delete_object(key.as_str()).await {
+                println!("Failed to delete {key}: {err:?}");
+            }
+        }
+    }
+}
+
+// Variants that lost their timeline or were removed would otherwise keep the old image forever.
+// The map is already saved without them, so a failed delete only leaves an unused object
+async fn remove_stale_timelines(old: &BeatMap, beatmap: &BeatMap) {
+    for (i, variant) in old.difficulties.iter().enumerate() {
+        if !variant.timeline || beatmap.difficulties.get(i).is_some_and(|variant| variant.timeline) {
+            continue;
+        }
+        let key = get_timeline_key(&old.id, i);
+        if let Err(err) = data().await.amazon.delete_object(key.as_str()).await {
+            println!("Failed to delete {key}: {err:?}");
+        }
+    }
+}
+
+// Ratings follow variants by name, since they're stored with them
+fn carry_ratings(beatmap: &mut BeatMap, old: &BeatMap) {
+    beatmap.rating_version = old.rating_version + 1;
+    for variant in &mut beatmap.difficulties {
+        if let Some(old) = old.difficulties.iter().find(|old| old.display == variant.display) {
+            variant.community_difficulty = old.community_difficulty;
+            variant.difficulty_votes = old.difficulty_votes;
+            variant.difficulty_total = old.difficulty_total;
+        }
+    }
+}
+
+// Old archives are kept so earlier versions can still be downloaded
+fn get_versions(
+    existing: Option<&BeatMap>,
+    beatmap: &BeatMap,
+    changelog: String,
+    changes: Option<DiffSummary>,
+) -> Vec<MapVersion> {
+    let mut versions = existing.map_or(vec![], BeatMap::get_versions);
+    match versions.last() {
+        Some(last) if last.archive_hash == beatmap.archive_hash => {}
+        _ => versions.push(MapVersion {
+            version: get_next_version(existing),
+            archive_hash: beatmap.archive_hash.clone(),
+            changelog,
+            upload_date: beatmap.upload_date,
+            changes,
+        }),
+    }
+    versions
+}
+
+fn get_next_version(existing: Option<&BeatMap>) -> u32 {
+    existing.and_then(|map| map.get_versions().last().map(|last| last.version + 1)).unwrap_or(1)
+}
+
+// Compared against the stored archive before it's replaced, the diff is stored under the new version.
+// A failed diff doesn't stop the upload, the version just has no changes listed
+async fn save_diff(old: &BeatMap, archive: &[u8]) -> Option<DiffSummary> {
+    let diff = match data().await.amazon.get_object(old.get_archive_key().as_str()).await {
+        Ok(old_archive) => diff_archives(&old_archive, archive),
+        Err(err) => Err(err),
+    };
+    let diff = diff.map_err(|err| println!("Failed to diff {}: {err:?}", old.id)).ok()?;
+    let key = get_diff_key(&old.id, get_next_version(Some(old)));
+    let saved = match serde_json::to_vec(&diff) {
+        Ok(json) => data().await.amazon.upload_object(json, key.as_str()).await.map(|_| ()),
+        Err(err) => Err(err.into()),
+    };
+    saved.map_err(|err| println!("Failed to save diff {key}: {err:?}")).ok()?;
+    Some(diff.summary())
+}
+
+pub fn create_beatmaps(
     beatmap: &mut Vec<u8>,
     charter_id: UserID,
-) -> Result<(BeatMap, Option<Vec<u8>>, Option<BackgroundData>), APIError> {
-    let file_data = parse_archive(get_parser(beatmap)?.deref_mut()).map_err(APIError::ZipError)?;
-    check_archive(beatmap).map_err(APIError::ZipError)?;
+) -> Result<Vec<LevelUpload>, APIError> {
+    parse_archive(get_parser(beatmap)?.deref_mut())
+        .map_err(|err| err.downcast().unwrap_or_else(APIError::ZipError))?
+        .into_iter()
+        .map(|file_data| create_beatmap(file_data, charter_id))
+        .collect()
+}
+
+pub fn create_beatmap(file_data: FileData, charter_id: UserID) -> Result<LevelUpload, APIError> {
+    let hashes = get_content_hashes(&file_data.archive).map_err(APIError::ZipError)?;
+
+    let mut difficulties = file_data
+        .level_data
+        .difficulty
+        .map_or(file_data.level_data.variants, |diff| vec![diff.into()]);
+    for (variant, timeline) in difficulties.iter_mut().zip(&file_data.timelines) {
+        variant.timeline = timeline.is_some();
+    }
 
-    Ok((
-        BeatMap {
+    Ok(LevelUpload {
+        beatmap: BeatMap {
             song: file_data.level_data.song_name,
             artist: file_data.level_data.artist,
             charter: file_data.level_data.charter,
-            difficulties: file_data
-                .level_data
-                .difficulty
-                .map_or(file_data.level_data.variants, |diff| vec![diff.into()]),
+            difficulties,
             description: file_data.level_data.description,
             artist_list: file_data.level_data.artist_list,
             charter_uid: charter_id,
             image: file_data.image.is_some(),
+            image_sizes: vec![],
+            share_card: false,
+            palette: vec![],
+            image_hash: None,
+            chart_hashes: hashes.charts,
+            audio_hashes: hashes.audio,
+            archive_hash: None,
+            pack_id: None,
+            versions: vec![],
             upvotes: 0,
+            downvotes: 0,
+            score: 0.0,
+            downloads: 0,
+            rating: None,
+            ratings: 0,
+            rating_total: 0,
+            rating_version: 0,
             upload_date: DateTime::from(SystemTime::now()),
             update_date: DateTime::from(SystemTime::now()),
             id: Uuid::new_v4(),
         },
-        file_data.image,
-        file_data.level_data.bg_data,
-    ))
+        archive: file_data.archive,
+        image: file_data.image,
+        bg_data: file_data.level_data.bg_data,
+        timelines: file_data.timelines,
+    })
+}
+
+/// What to search for to find the uploaded maps, the charter's name for packs.
+pub fn get_search_query(maps: &[BeatMap]) -> String {
+    match maps {
+        [map] => format!("{} {}", map.charter, map.song),
+        maps => maps.first().map_or(String::new(), |map| map.charter.clone()),
+    }
 }
@@ -1,55 +1,308 @@
 use crate::api::APIError;
-use crate::util::amazon::{MAPS_TABLE_NAME, USERS_TABLE_NAME};
-use crate::util::database::{BeatMap, User};
-use aws_sdk_dynamodb::types::AttributeValue;
+use crate::api::flags::check_admin;
+use crate::api::notifications::schedule_notifications;
+use crate::api::stats::record_stat;
+use crate::util::amazon::{is_condition_failure, MAPS_TABLE_NAME, USERS_TABLE_NAME};
+use crate::util::database::{BeatMap, MapID, NotificationEvent, User};
+use aws_sdk_dynamodb::types::{AttributeValue, Update};
+use std::collections::HashMap;
 use warp::{Rejection, Reply};
 use crate::util::data;
 use crate::util::warp::Replyable;
 
 pub async fn upvote(
-    user: User,
+    mut user: User,
     map: BeatMap
 ) -> Result<impl Reply, Rejection> {
-    upvote_for_map(&map, &user).await?;
+    upvote_for_map(&map, &mut user).await?;
     Ok("Ok".reply())
 }
 
-pub async fn upvote_for_map(map: &BeatMap, user: &User) -> Result<(), APIError> {
-    if user.upvoted.contains(&map.id) {
-        return Err(APIError::AlreadyUpvoted());
-    }
+pub async fn unvote(
+    mut user: User,
+    map: BeatMap
+) -> Result<impl Reply, Rejection> {
+    unvote_for_map(&map, &mut user).await?;
+    Ok("Ok".reply())
+}
 
-    data().await.amazon
-        .update(MAPS_TABLE_NAME, map.id.to_string(), |builder| {
-            builder
-                .update_expression("SET upvotes = if_not_exists(upvotes, :start) + :inc")
-                .expression_attribute_values(":start", AttributeValue::N("0".to_string()))
-                .expression_attribute_values(":inc", AttributeValue::N("1".to_string()))
-        })
-        .await.map_err(APIError::database_error)?;
-    data().await.amazon.add_to_list(USERS_TABLE_NAME, user.id.to_string(), "upvoted", map.id.to_string()).await?;
-    Ok(())
+pub async fn downvote(
+    mut user: User,
+    map: BeatMap
+) -> Result<impl Reply, Rejection> {
+    downvote_for_map(&map, &mut user).await?;
+    Ok("Ok".reply())
 }
 
-pub async fn unvote(
+pub async fn undownvote(
     mut user: User,
     map: BeatMap
 ) -> Result<impl Reply, Rejection> {
-    unvote_for_map(&map, &mut user).await?;
+    remove_vote(&map, &mut user, Vote::Down).await?;
     Ok("Ok".reply())
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum Vote {
+    Up,
+    Down,
+}
+
+impl Vote {
+    // The user's list of maps with this vote
+    fn list(&self) -> &'static str {
+        match self {
+            Vote::Up => "upvoted",
+            Vote::Down => "downvoted",
+        }
+    }
+
+    // The map's count of this vote
+    fn counter(&self) -> &'static str {
+        match self {
+            Vote::Up => "upvotes",
+            Vote::Down => "downvotes",
+        }
+    }
+
+    pub fn get_votes<'a>(&self, user: &'a mut User) -> &'a mut Vec<MapID> {
+        match self {
+            Vote::Up => &mut user.upvoted,
+            Vote::Down => &mut user.downvoted,
+        }
+    }
+
+    // Upvotes keep their old error, since clients already check for it
+    fn already_voted(&self) -> APIError {
+        match self {
+            Vote::Up => APIError::AlreadyUpvoted(),
+            Vote::Down => APIError::AlreadyVoted(),
+        }
+    }
+
+    fn opposite(&self) -> Vote {
+        match self {
+            Vote::Up => Vote::Down,
+            Vote::Down => Vote::Up,
+        }
+    }
+}
+
+pub async fn upvote_for_map(map: &BeatMap, user: &mut User) -> Result<(), APIError> {
+    add_vote(map, user, Vote::Up).await
+}
+
 pub async fn unvote_for_map(map: &BeatMap, user: &mut User) -> Result<(), APIError> {
-    user.upvoted.remove(user.upvoted.iter().position(|elem| elem == &map.id).ok_or(APIError::AlreadyUpvoted())?);
+    remove_vote(map, user, Vote::Up).await
+}
+
+pub async fn downvote_for_map(map: &BeatMap, user: &mut User) -> Result<(), APIError> {
+    add_vote(map, user, Vote::Down).await
+}
 
+// The user's lists are the source of truth, the counts only change along with them
+async fn add_vote(map: &BeatMap, user: &mut User, vote: Vote) -> Result<(), APIError> {
+    if vote.get_votes(user).contains(&map.id) {
+        return Err(vote.already_voted());
+    }
+    // Voting the other way replaces the old vote
+    if vote.opposite().get_votes(user).contains(&map.id) {
+        remove_vote(map, user, vote.opposite()).await?;
+    }
+
+    let map_id = AttributeValue::S(map.id.to_string());
+    let updated = data().await.amazon
+        .transact(vec![
+            Update::builder()
+                .table_name(USERS_TABLE_NAME)
+                .key("id", AttributeValue::S(user.id.to_string()))
+                .update_expression("SET #list = list_append(if_not_exists(#list, :empty_list), :new_value)")
+                .condition_expression("NOT contains(#list, :map)")
+                .expression_attribute_names("#list", vote.list())
+                .expression_attribute_values(":empty_list", AttributeValue::L(vec![]))
+                .expression_attribute_values(":new_value", AttributeValue::L(vec![map_id.clone()]))
+                .expression_attribute_values(":map", map_id)
+                .build()
+                .map_err(APIError::database_error)?,
+            Update::builder()
+                .table_name(MAPS_TABLE_NAME)
+                .key("id", AttributeValue::S(map.id.to_string()))
+                .update_expression("SET #counter = if_not_exists(#counter, :start) + :inc")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_names("#counter", vote.counter())
+                .expression_attribute_values(":start", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":inc", AttributeValue::N("1".to_string()))
+                .build()
+                .map_err(APIError::database_error)?,
+        ])
+        .await
+        .map_err(APIError::database_error)?;
+    if !updated {
+        return Err(vote.already_voted());
+    }
+    vote.get_votes(user).push(map.id);
+    record_stat(map, vote.counter(), 1).await?;
+    if vote == Vote::Up && map.charter_uid != user.id {
+        schedule_notifications(map.clone(), NotificationEvent::Upvote, user.id);
+    }
+    update_score(&map.id).await
+}
+
+pub async fn remove_vote(map: &BeatMap, user: &mut User, vote: Vote) -> Result<(), APIError> {
+    let index = vote.get_votes(user).iter().position(|elem| elem == &map.id).ok_or(vote.already_voted())?;
+
+    // Fails if the list changed since the user was loaded, so the wrong entry is never removed
+    let updated = data().await.amazon
+        .transact(vec![
+            Update::builder()
+                .table_name(USERS_TABLE_NAME)
+                .key("id", AttributeValue::S(user.id.to_string()))
+                .update_expression(format!("REMOVE #list[{index}]"))
+                .condition_expression(format!("#list[{index}] = :map"))
+                .expression_attribute_names("#list", vote.list())
+                .expression_attribute_values(":map", AttributeValue::S(map.id.to_string()))
+                .build()
+                .map_err(APIError::database_error)?,
+            Update::builder()
+                .table_name(MAPS_TABLE_NAME)
+                .key("id", AttributeValue::S(map.id.to_string()))
+                .update_expression("SET #counter = #counter - :dec")
+                .condition_expression("#counter > :start")
+                .expression_attribute_names("#counter", vote.counter())
+                .expression_attribute_values(":start", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":dec", AttributeValue::N("1".to_string()))
+                .build()
+                .map_err(APIError::database_error)?,
+        ])
+        .await
+        .map_err(APIError::database_error)?;
+    if !updated {
+        return Err(vote.already_voted());
+    }
+    vote.get_votes(user).remove(index);
+    record_stat(map, vote.counter(), -1).await?;
+    update_score(&map.id).await
+}
+
+/// Lower bound of the 95% Wilson score interval, so a few votes can't outrank many mostly-positive ones.
+pub fn get_wilson_score(upvotes: u64, downvotes: u64) -> f64 {
+    let total = (upvotes + downvotes) as f64;
+    if total == 0.0 {
+        return 0.0;
+    }
+    let z = 1.96f64;
+    let positive = upvotes as f64 / total;
+    (positive + z * z / (2.0 * total)
+        - z * ((positive * (1.0 - positive) + z * z / (4.0 * total)) / total).sqrt())
+        / (1.0 + z * z / total)
+}
+
+// DynamoDB can't take square roots, so the score is recomputed from a fresh read. The write only
+// goes through if the counts haven't changed since, otherwise the newer vote updates it instead.
+async fn update_score(id: &MapID) -> Result<(), APIError> {
+    let Some(map) = data().await.amazon.get_item::<BeatMap>(MAPS_TABLE_NAME, id.to_string()).await
+        .map_err(APIError::database_error)? else {
+        return Ok(());
+    };
     data().await.amazon
         .update(MAPS_TABLE_NAME, map.id.to_string(), |builder| {
             builder
-                .update_expression("SET upvotes = :start - :dec")
-                .expression_attribute_values(":start", AttributeValue::N(map.upvotes.to_string()))
-                .expression_attribute_values(":dec", AttributeValue::N("1".to_string()))
+                .update_expression("SET score = :score")
+                .condition_expression("upvotes = :upvotes AND (attribute_not_exists(downvotes) OR downvotes = :downvotes)")
+                .expression_attribute_values(":score", AttributeValue::N(get_wilson_score(map.upvotes, map.downvotes).to_string()))
+                .expression_attribute_values(":upvotes", AttributeValue::N(map.upvotes.to_string()))
+                .expression_attribute_values(":downvotes", AttributeValue::N(map.downvotes.to_string()))
         })
-        .await.map_err(APIError::database_error)?;
-    data().await.amazon.overwrite_list(USERS_TABLE_NAME, user.id.to_string(), "upvoted", user.upvoted.clone()).await?;
-    Ok(())
+        .await
+        .or_else(|err| match is_condition_failure(&err) {
+            true => Ok(()),
+            false => Err(APIError::database_error(err)),
+        })
+}
+
+pub async fn repair(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    Ok(repair_upvotes().await?.reply())
+}
+
+/// Recounts every map's votes from the users' lists and rescores them, returning how many were wrong.
+/// Maps whose votes change while this runs are skipped, the next run picks them up.
+async fn repair_upvotes() -> Result<usize, APIError> {
+    // Maps are read first, so any vote newer than the map's counts changes them and fails the write.
+    // Both reads are consistent, so no vote from before the users are read can be missed.
+    let maps: Vec<BeatMap> = data().await.amazon.scan_consistent(MAPS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    let users: Vec<User> = data().await.amazon.scan_consistent(USERS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    let mut upvotes: HashMap<MapID, u64> = HashMap::new();
+    for map in users.iter().flat_map(|user| &user.upvoted) {
+        *upvotes.entry(*map).or_default() += 1;
+    }
+    let mut downvotes: HashMap<MapID, u64> = HashMap::new();
+    for map in users.iter().flat_map(|user| &user.downvoted) {
+        *downvotes.entry(*map).or_default() += 1;
+    }
+
+    let mut repaired = 0;
+    for map in maps {
+        let upvotes = upvotes.get(&map.id).copied().unwrap_or_default();
+        let downvotes = downvotes.get(&map.id).copied().unwrap_or_default();
+        let score = get_wilson_score(upvotes, downvotes);
+        if map.upvotes == upvotes && map.downvotes == downvotes && map.score == score {
+            continue;
+        }
+        println!("Repairing votes of {} from {}/{} to {upvotes}/{downvotes}", map.id, map.upvotes, map.downvotes);
+        let updated = data().await.amazon
+            .update(MAPS_TABLE_NAME, map.id.to_string(), |builder| {
+                builder
+                    .update_expression("SET upvotes = :upvotes, downvotes = :downvotes, score = :score")
+                    .condition_expression("attribute_exists(id) \
+                        AND (attribute_not_exists(upvotes) OR upvotes = :old_upvotes) \
+                        AND (attribute_not_exists(downvotes) OR downvotes = :old_downvotes)")
+                    .expression_attribute_values(":upvotes", AttributeValue::N(upvotes.to_string()))
+                    .expression_attribute_values(":downvotes", AttributeValue::N(downvotes.to_string()))
+                    .expression_attribute_values(":score", AttributeValue::N(score.to_string()))
+                    .expression_attribute_values(":old_upvotes", AttributeValue::N(map.upvotes.to_string()))
+                    .expression_attribute_values(":old_downvotes", AttributeValue::N(map.downvotes.to_string()))
+            })
+            .await;
+        match updated {
+            Ok(()) => repaired += 1,
+            Err(err) if is_condition_failure(&err) => println!("Votes of {} changed while repairing, skipping", map.id),
+            Err(err) => return Err(APIError::database_error(err)),
+        }
+    }
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_votes_scores_zero() {
+        assert_eq!(get_wilson_score(0, 0), 0.0);
+    }
+
+    #[test]
+    fn score_stays_between_zero_and_one() {
+        for (upvotes, downvotes) in [(1, 0), (0, 1), (5, 5), (1000, 0), (0, 1000)] {
+            let score = get_wilson_score(upvotes, downvotes);
+            assert!((0.0..=1.0).contains(&score), "{upvotes}/{downvotes} scored {score}");
+        }
+        assert_eq!(get_wilson_score(0, 10), 0.0);
+    }
+
+    #[test]
+    fn many_mostly_positive_votes_beat_a_few_perfect_ones() {
+        assert!(get_wilson_score(90, 10) > get_wilson_score(3, 0));
+        assert!(get_wilson_score(10, 0) > get_wilson_score(1, 0));
+        assert!(get_wilson_score(10, 1) < get_wilson_score(10, 0));
+    }
+
+    #[test]
+    fn score_matches_the_wilson_lower_bound() {
+        assert!((get_wilson_score(1, 0) - 0.2065).abs() < 1e-4);
+        assert!((get_wilson_score(50, 50) - 0.4038).abs() < 1e-4);
+    }
 }
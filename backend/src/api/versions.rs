@@ -0,0 +1,120 @@
+use crate::api::upload::{create_beatmaps, read_form, upload_level};
+use crate::api::flags::check_admin;
+use crate::api::APIError;
+use crate::util::amazon::get_object_url;
+use crate::util::archive::migrate_archives;
+use crate::util::database::{get_archive_key, get_diff_key, MapVersion, User};
+use crate::util::ratelimiter::{SiteAction, UniqueIdentifier};
+use crate::util::warp::{get_map, get_user, Replyable};
+use crate::util::{data, schedule_pack_update, LockResultExt};
+use serde::Serialize;
+use std::ops::Deref;
+use std::time::Duration;
+use tokio::time::timeout;
+use warp::http::Uri;
+use warp::multipart::FormData;
+use warp::{redirect, Rejection, Reply};
+
+#[derive(Serialize)]
+pub struct VersionInfo {
+    #[serde(flatten)]
+    pub version: MapVersion,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct UploadedVersion {
+    #[serde(flatten)]
+    pub version: Option<MapVersion>,
+    pub warnings: Vec<String>,
+}
+
+pub async fn versions(id: String) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    Ok(map
+        .get_versions()
+        .into_iter()
+        .map(|version| VersionInfo {
+            url: get_object_url(&get_archive_key(&map.id, &version.archive_hash)),
+            version,
+        })
+        .collect::<Vec<_>>()
+        .reply())
+}
+
+pub async fn download_version(id: String, version: u32) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    let version = map
+        .get_versions()
+        .into_iter()
+        .find(|found| found.version == version)
+        .ok_or(APIError::UnknownVersion())?;
+    let url = get_object_url(&get_archive_key(&map.id, &version.archive_hash));
+    Ok(redirect::temporary(Uri::try_from(url).map_err(APIError::database_error)?))
+}
+
+/// Everything that changed in the version, versions without a stored diff have none.
+pub async fn version_diff(id: String, version: u32) -> Result<impl Reply, Rejection> {
+    let map = get_map(id).await?;
+    map.get_versions()
+        .into_iter()
+        .find(|found| found.version == version && found.changes.is_some())
+        .ok_or(APIError::UnknownVersion())?;
+    let url = get_object_url(&get_diff_key(&map.id, version));
+    Ok(redirect::temporary(Uri::try_from(url).map_err(APIError::database_error)?))
+}
+
+/// Replaces the map's archive, keeping the old one as a numbered version.
+/// Unlike a normal upload the map is picked by id, so the song can be renamed.
+pub async fn upload_version(
+    id: String,
+    identifier: UniqueIdentifier,
+    form: FormData,
+) -> Result<impl Reply, Rejection> {
+    let mut beatmap = None;
+    let mut token = None;
+    let mut changelog = None;
+    for (name, data) in read_form(form).await? {
+        match name.as_str() {
+            "beatmap" => beatmap = Some(data),
+            "firebaseToken" => token = Some(data),
+            "changelog" => changelog = Some(String::from_utf8_lossy(&data).trim().to_string()),
+            _ => return Err(APIError::ArgumentError().into()),
+        }
+    }
+
+    let user = get_user(String::from_utf8_lossy(token.ok_or(APIError::ArgumentError())?.deref()).to_string()).await?;
+    let map = get_map(id).await?;
+    if map.charter_uid != user.id {
+        return Err(APIError::PermissionError().into());
+    }
+    data().await.ratelimiter
+        .lock()
+        .ignore_poison()
+        .check_limited(SiteAction::Update, &identifier)?;
+
+    let mut beatmap = beatmap.ok_or(APIError::ArgumentError())?;
+    let [level]: [_; 1] = create_beatmaps(&mut beatmap, user.id)?
+        .try_into()
+        .map_err(|_| APIError::VersionLevelError())?;
+    let pack_id = map.pack_id;
+    let mut warnings = vec![];
+    let map = timeout(
+        Duration::from_millis(10000),
+        upload_level(level, Some(map), pack_id, changelog.unwrap_or_default(), &mut warnings),
+    )
+    .await.map_err(APIError::TimeoutError)??;
+    if let Some(pack_id) = &pack_id {
+        schedule_pack_update(*pack_id);
+    }
+    Ok(UploadedVersion {
+        version: map.get_versions().pop(),
+        warnings,
+    }.reply())
+}
+
+/// Moves archives from before hashing to their hashed keys, see `migrate_archives`.
+pub async fn migrate(user: User) -> Result<impl Reply, Rejection> {
+    check_admin(&user)?;
+    Ok(migrate_archives().await?.reply())
+}
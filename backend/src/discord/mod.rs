@@ -1,6 +1,6 @@
 mod backlogger;
 
-use crate::api::upload::{upload_beatmap, MAX_SIZE};
+use crate::api::upload::{get_search_query, upload_beatmap, MAX_SIZE};
 use crate::api::upvote::upvote_for_map;
 use crate::api::APIError;
 use crate::discord::backlogger::update_backlog;
@@ -151,19 +151,22 @@ impl Handler {
         upvotes: HashSet<UserId>,
     ) -> Result<String, APIError> {
         let user = get_user_from_link(AccountLink::Discord(user_id)).await?;
-        let map = upload_beatmap(
+        let upload = upload_beatmap(
             file?,
             UniqueIdentifier::Discord(user_id),
             user.id,
         )
             .await?;
-        if map.upvotes == 0 {
+        for warning in &upload.warnings {
+            println!("Upload warning for {user_id}: {warning}");
+        }
+        for map in upload.maps.iter().filter(|map| map.upvotes == 0) {
             for user in &upvotes {
-                let user = get_user_from_link(AccountLink::Discord(user.get())).await?;
-                upvote_for_map(&map, &user).await?;
+                let mut user = get_user_from_link(AccountLink::Discord(user.get())).await?;
+                upvote_for_map(map, &mut user).await?;
             }
         }
-        Ok(format!("{} {}", map.charter, map.song))
+        Ok(get_search_query(&upload.maps))
     }
 }
 
@@ -3,23 +3,40 @@ mod discord;
 mod parsing;
 mod util;
 
+use crate::api::bundle::bundle;
+use crate::api::collections::{
+    add_to_collection, collection, create_collection, delete_collection, download_collection, edit_collection,
+    remove_from_collection, reorder_collection, user_collections,
+};
+use crate::api::comments::{comments, delete_comment, edit_comment, post_comment};
 use crate::api::delete::delete;
-use crate::api::downloaded::{download, remove};
+use crate::api::details::map_details;
+use crate::api::downloaded::{download, download_archive, index_all_downloads, remove};
+use crate::api::edit::edit_map;
+use crate::api::feed::{feed, follow, index_all_follows, read_feed, unfollow};
+use crate::api::flags::{dismiss_flag, flags, index_all_hashes};
+use crate::api::mappage::map_page;
+use crate::api::notifications::{notifications, read_notification, read_notifications};
+use crate::api::ratings::rate;
 use crate::api::search::search;
+use crate::api::stats::{charter_stats, map_stats};
 use crate::api::signin::{discord_signin, discord_sync, google_signin, google_sync};
 use crate::api::upload::upload;
-use crate::api::upvote::{unvote, upvote};
+use crate::api::upvote::{downvote, repair, undownvote, unvote, upvote};
 use crate::api::usersongs::usersongs;
+use crate::api::versions::{download_version, migrate, upload_version, version_diff, versions};
 use crate::discord::run_bot;
 use crate::util::amazon::Amazon;
 use crate::util::database::User;
 use crate::util::ratelimiter::{Ratelimiter, SiteAction};
-use crate::util::warp::{check_ratelimit, extract_identifier, extract_map, handle_auth, handle_error, Replyable};
+use crate::util::warp::{check_ratelimit, extract_identifier, extract_map, handle_auth, handle_error, optional_user, Replyable};
 use firebase_auth::FirebaseAuth;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use warp::path::param;
-use warp::{get, multipart, path, post, Filter};
+use warp::body::json;
+use uuid::Uuid;
+use warp::{get, header, multipart, patch, path, post, query, Filter};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -33,6 +50,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let limit_param = |action, path| limit(action, path).and(get()).and(param::<String>());
     let auth = |action, path| limit(action, path).and(post()).and(handle_auth());
     let auth_map = |action, path| limit(action, path).and(post()).and(extract_map()).untuple_one();
+    let map = |action| path("api")
+        .and(path("maps"))
+        .and(check_ratelimit(action))
+        .untuple_one()
+        .and(param::<String>());
+    let map_route = move |action, in_path: &'static str| map(action).and(path(in_path));
+    let collections = |action| path("api").and(path("collections")).and(check_ratelimit(action)).untuple_one();
+    let collection_route = move |action, in_path: &'static str| collections(action)
+        .and(param::<String>())
+        .and(path(in_path))
+        .and(path::end());
 
     warp::serve(
         auth(SiteAction::UpvoteList, "account_data")
@@ -40,19 +68,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .or(auth_map(SiteAction::Search, "delete").and_then(delete))
             .or(auth_map(SiteAction::Download, "download").and_then(download))
             .or(auth_map(SiteAction::Download, "remove").and_then(remove))
-            .or(limit_param(SiteAction::Search, "search").and_then(search))
+            .or(limit_param(SiteAction::Search, "search").and(query()).and_then(search))
             .or(auth_map(SiteAction::Search, "upvote").and_then(upvote))
             .or(auth_map(SiteAction::Search, "unvote").and_then(unvote))
+            .or(auth_map(SiteAction::Search, "downvote").and_then(downvote))
+            .or(auth_map(SiteAction::Search, "undownvote").and_then(undownvote))
             .or(limit(SiteAction::Search, "upload")
                 .and(post())
                 .and(extract_identifier())
                 .and(multipart::form())
                 .and_then(upload))
+            .or(limit(SiteAction::Bundle, "bundle").and(post()).and(json()).and_then(bundle))
+            .or(collections(SiteAction::Search).and(path::end()).and(post()).and(json()).and_then(create_collection))
+            .or(collections(SiteAction::Search)
+                .and(param::<String>())
+                .and(path::end())
+                .and(get())
+                .and(optional_user())
+                .and_then(collection))
+            .or(collections(SiteAction::Search).and(param::<String>()).and(path::end()).and(patch()).and(json()).and_then(edit_collection))
+            .or(collections(SiteAction::Search)
+                .and(param::<String>())
+                .and(path::end())
+                .and(warp::delete())
+                .and(json())
+                .and_then(delete_collection))
+            .or(collection_route(SiteAction::Search, "add").and(post()).and(json()).and_then(add_to_collection))
+            .or(collection_route(SiteAction::Search, "remove").and(post()).and(json()).and_then(remove_from_collection))
+            .or(collection_route(SiteAction::Search, "reorder").and(post()).and(json()).and_then(reorder_collection))
+            .or(collection_route(SiteAction::Bundle, "download").and(get()).and(optional_user()).and_then(download_collection))
+            .or(limit_param(SiteAction::Search, "usercollections").and(optional_user()).and_then(user_collections))
             .or(limit_param(SiteAction::Search, "usersongs").and_then(usersongs))
+            .or(auth(SiteAction::UpvoteList, "follow").and(param()).and_then(follow))
+            .or(auth(SiteAction::UpvoteList, "unfollow").and(param()).and_then(unfollow))
+            .or(limit(SiteAction::Search, "feed").and(path::end()).and(get()).and(optional_user()).and(query()).and_then(feed))
+            .or(auth(SiteAction::UpvoteList, "readfeed").and_then(read_feed))
+            .or(limit(SiteAction::Search, "notifications")
+                .and(path::end())
+                .and(get())
+                .and(optional_user())
+                .and(query())
+                .and_then(notifications))
+            .or(auth(SiteAction::UpvoteList, "readnotification").and(param()).and_then(read_notification))
+            .or(auth(SiteAction::UpvoteList, "readnotifications").and_then(read_notifications))
+            .or(auth(SiteAction::UpvoteList, "flags").and_then(flags))
+            .or(auth(SiteAction::UpvoteList, "repairupvotes").and_then(repair))
+            .or(auth(SiteAction::UpvoteList, "migratearchives").and_then(migrate))
+            .or(auth(SiteAction::UpvoteList, "indexhashes").and_then(index_all_hashes))
+            .or(auth(SiteAction::UpvoteList, "indexfollows").and_then(index_all_follows))
+            .or(auth(SiteAction::UpvoteList, "indexdownloads").and_then(index_all_downloads))
+            .or(auth(SiteAction::UpvoteList, "dismissflag").and(param()).and_then(dismiss_flag))
             .or(limit_param(SiteAction::UpvoteList, "discordauth").and_then(discord_signin))
             .or(auth(SiteAction::UpvoteList, "discordsync").and(param()).and_then(discord_sync))
             .or(limit_param(SiteAction::UpvoteList, "googleauth").and_then(google_signin))
             .or(auth(SiteAction::UpvoteList, "googlesync").and(param()).and_then(google_sync))
+            .or(map(SiteAction::Search).and(path::end()).and(get()).and(optional_user()).and_then(map_details))
+            .or(map(SiteAction::Search).and(path::end()).and(patch()).and(json()).and_then(edit_map))
+            .or(map_route(SiteAction::Download, "download")
+                .and(path::end())
+                .and(get())
+                .and(header::optional::<String>("range"))
+                .and(extract_identifier())
+                .and(optional_user())
+                .and_then(download_archive))
+            .or(map_route(SiteAction::Search, "comments").and(path::end()).and(get()).and_then(comments))
+            .or(map_route(SiteAction::Search, "comments").and(path::end()).and(post()).and(json()).and_then(post_comment))
+            .or(map_route(SiteAction::Search, "comments")
+                .and(param::<Uuid>())
+                .and(path::end())
+                .and(patch())
+                .and(json())
+                .and_then(edit_comment))
+            .or(map_route(SiteAction::Search, "comments")
+                .and(param::<Uuid>())
+                .and(path::end())
+                .and(warp::delete())
+                .and(json())
+                .and_then(delete_comment))
+            .or(map_route(SiteAction::Search, "ratings").and(path::end()).and(post()).and(json()).and_then(rate))
+            .or(map_route(SiteAction::Search, "stats").and(path::end()).and(get()).and_then(map_stats))
+            .or(limit_param(SiteAction::Search, "charterstats").and_then(charter_stats))
+            .or(map_route(SiteAction::Search, "versions")
+                .and(post())
+                .and(path::end())
+                .and(extract_identifier())
+                .and(multipart::form())
+                .and_then(upload_version))
+            .or(map_route(SiteAction::Search, "versions").and(get()).and(path::end()).and_then(versions))
+            .or(map_route(SiteAction::Download, "versions")
+                .and(get())
+                .and(param::<u32>())
+                .and(path::end())
+                .and_then(download_version))
+            .or(map_route(SiteAction::Search, "versions")
+                .and(get())
+                .and(param::<u32>())
+                .and(path("diff"))
+                .and(path::end())
+                .and_then(version_diff))
+            .or(path("map")
+                .and(check_ratelimit(SiteAction::Search))
+                .untuple_one()
+                .and(get())
+                .and(param::<String>())
+                .and(path::end())
+                .and_then(map_page))
             .or(warp::fs::dir(std::env::args().nth(2).unwrap()))
             .recover(handle_error),
     )
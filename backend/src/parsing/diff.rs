@@ -0,0 +1,324 @@
+use crate::parsing::timeline::{is_note, read_chart_events};
+use crate::parsing::{get_level_root, is_junk, LevelData, LevelVariant};
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct VersionDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub metadata: Vec<FieldChange>,
+    pub charts: Vec<ChartDiff>,
+}
+
+// How much changed, kept on the version while the full diff is stored on its own
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiffSummary {
+    pub files: usize,
+    pub metadata: usize,
+    pub charts: usize,
+}
+
+impl VersionDiff {
+    pub fn summary(&self) -> DiffSummary {
+        DiffSummary {
+            files: self.added.len() + self.removed.len() + self.modified.len(),
+            metadata: self.metadata.len(),
+            charts: self.charts.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct ChartDiff {
+    pub variant: String,
+    pub chart: String,
+    pub notes_added: usize,
+    pub notes_removed: usize,
+    // Notes that are otherwise unchanged but happen at a different time
+    pub notes_moved: usize,
+    // Set when every moved note moved by the same number of beats
+    pub shift: Option<f64>,
+    pub tempo_changed: bool,
+}
+
+struct Level {
+    // File hashes by path relative to the level's folder
+    files: BTreeMap<String, String>,
+    contents: HashMap<String, Vec<u8>>,
+    metadata: BTreeMap<String, Value>,
+    variants: Vec<LevelVariant>,
+    events: Vec<Value>,
+}
+
+/// Compares two level archives, reporting changed files, metadata and charts.
+pub fn diff_archives(old: &[u8], new: &[u8]) -> Result<VersionDiff, Error> {
+    let old = read_level(old)?;
+    let new = read_level(new)?;
+    let mut diff = VersionDiff::default();
+
+    for (name, hash) in &new.files {
+        match old.files.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(old_hash) if old_hash != hash => diff.modified.push(name.clone()),
+            _ => {}
+        }
+    }
+    diff.removed = old.files.keys().filter(|name| !new.files.contains_key(*name)).cloned().collect();
+
+    let fields: BTreeSet<&String> = old.metadata.keys().chain(new.metadata.keys()).collect();
+    for field in fields {
+        let old_value = old.metadata.get(field).cloned().unwrap_or_default();
+        let new_value = new.metadata.get(field).cloned().unwrap_or_default();
+        if old_value != new_value {
+            diff.metadata.push(FieldChange {
+                field: field.clone(),
+                old: old_value,
+                new: new_value,
+            });
+        }
+    }
+
+    // Variants are matched by their chart file, since the display name is often what changed
+    let charts: Vec<(&str, &str)> = new
+        .variants
+        .iter()
+        .chain(&old.variants)
+        .map(|variant| (variant.chart_file(), variant.display.as_str()))
+        .collect();
+    let mut seen = BTreeSet::new();
+    for (chart, display) in charts {
+        if !seen.insert(chart) {
+            continue;
+        }
+        let chart_diff = diff_chart(&old, &new, chart);
+        if chart_diff.notes_added + chart_diff.notes_removed + chart_diff.notes_moved > 0 || chart_diff.tempo_changed {
+            diff.charts.push(ChartDiff {
+                variant: display.to_string(),
+                ..chart_diff
+            });
+        }
+    }
+    Ok(diff)
+}
+
+fn diff_chart(old: &Level, new: &Level, chart: &str) -> ChartDiff {
+    let (old_notes, old_tempo) = read_chart(old, chart);
+    let (new_notes, new_tempo) = read_chart(new, chart);
+
+    // Drop notes that didn't change at all, notes are compared by their serialized form
+    let mut unchanged: HashMap<String, usize> = HashMap::new();
+    for note in &new_notes {
+        *unchanged.entry(note.to_string()).or_default() += 1;
+    }
+    let old_notes: Vec<&Value> = old_notes.iter().filter(|note| !take(&mut unchanged, note.to_string())).collect();
+    let new_notes: Vec<&Value> = new_notes.iter().filter(|note| take(&mut unchanged, note.to_string())).collect();
+
+    // The rest are moved if a note with everything but the time matching is left
+    let mut moved: HashMap<String, VecDeque<f64>> = HashMap::new();
+    for note in &old_notes {
+        moved.entry(without_time(note)).or_default().push_back(get_time(note));
+    }
+    let mut shifts = vec![];
+    let mut notes_added = 0;
+    for note in new_notes {
+        match moved.get_mut(&without_time(note)).and_then(VecDeque::pop_front) {
+            Some(time) => shifts.push(get_time(note) - time),
+            None => notes_added += 1,
+        }
+    }
+
+    let shift = shifts
+        .first()
+        .filter(|first| shifts.iter().all(|shift| (shift - *first).abs() < 1e-6))
+        .copied();
+    ChartDiff {
+        chart: chart.to_string(),
+        notes_added,
+        notes_removed: old_notes.len() - shifts.len(),
+        notes_moved: shifts.len(),
+        shift,
+        tempo_changed: old_tempo != new_tempo,
+        ..Default::default()
+    }
+}
+
+// Uses up one of the key's remaining matches
+fn take(counts: &mut HashMap<String, usize>, key: String) -> bool {
+    match counts.get_mut(&key) {
+        Some(count) if *count > 0 => {
+            *count -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+// Returns the chart's notes and its tempo changes, a missing or broken chart has neither
+fn read_chart(level: &Level, chart: &str) -> (Vec<Value>, Vec<String>) {
+    let Some(events) = level.contents.get(&chart.to_ascii_lowercase())
+        .and_then(|content| read_chart_events(content).ok()) else {
+        return (vec![], vec![]);
+    };
+    let notes = events
+        .iter()
+        .filter(|event| event["type"].as_str().is_some_and(is_note))
+        .cloned()
+        .collect();
+    let mut tempo: Vec<String> = events
+        .iter()
+        .chain(&level.events)
+        .filter(|event| event["type"] == "setBPM")
+        .map(Value::to_string)
+        .collect();
+    tempo.sort();
+    (notes, tempo)
+}
+
+fn without_time(note: &Value) -> String {
+    let mut note = note.clone();
+    if let Some(note) = note.as_object_mut() {
+        note.remove("time");
+    }
+    note.to_string()
+}
+
+fn get_time(note: &Value) -> f64 {
+    note["time"].as_f64().unwrap_or_default()
+}
+
+fn read_level(file: &[u8]) -> Result<Level, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(file))?;
+    let names: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !is_junk(name))
+        .map(ToString::to_string)
+        .collect();
+    let root = get_level_root(names.iter().map(|name| name.replace('\\', "/")));
+
+    let mut level = Level {
+        files: BTreeMap::new(),
+        contents: HashMap::new(),
+        metadata: BTreeMap::new(),
+        variants: vec![],
+        events: vec![],
+    };
+    let mut level_file = None;
+    for name in names {
+        let Some(relative) = name.replace('\\', "/").strip_prefix(&root).map(ToString::to_string) else {
+            continue;
+        };
+        let mut data = Vec::new();
+        archive.by_name(&name)?.read_to_end(&mut data)?;
+        level.files.insert(relative.clone(), format!("{:x}", Sha256::digest(&data)));
+        // Only json is needed to compare charts, everything else is just hashed
+        if relative.to_ascii_lowercase().ends_with(".json") {
+            // level.json is preferred over manifest.json, like when parsing
+            let lowercase = relative.to_ascii_lowercase();
+            if lowercase == "level.json" || (level_file.is_none() && lowercase == "manifest.json") {
+                level_file = Some(data.clone());
+            }
+            level.contents.insert(relative.to_ascii_lowercase(), data);
+        }
+    }
+
+    let Some(level_file) = level_file else {
+        return Ok(level);
+    };
+    let json: Value = serde_json::from_slice(&level_file)?;
+    if let Some(metadata) = json["metadata"].as_object() {
+        level.metadata = metadata.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+    }
+    if let Ok(data) = serde_json::from_value::<LevelData>(json) {
+        level.variants = data.metadata.difficulty.map_or(data.metadata.variants, |diff| vec![diff.into()]);
+        level.events = data.events;
+    }
+    Ok(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn make_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn level(description: &str) -> String {
+        format!(r#"{{"metadata": {{"artist": "a", "charter": "c", "description": "{description}", "songName": "s",
+            "variants": [{{"display": "Easy", "difficulty": 3, "chart": "easy.json"}}]}}, "events": []}}"#)
+    }
+
+    #[test]
+    fn diff_reports_files_metadata_and_charts() {
+        let old = make_zip(&[
+            ("Song/level.json", &level("one")),
+            ("Song/easy.json", r#"[{"type": "block", "time": 1, "angle": 0}, {"type": "block", "time": 2, "angle": 90}, {"type": "mine", "time": 3}]"#),
+            ("Song/old.png", "x"),
+        ]);
+        let new = make_zip(&[
+            ("Other/level.json", &level("two")),
+            ("Other/easy.json", r#"[{"type": "block", "time": 1.5, "angle": 0}, {"type": "block", "time": 2.5, "angle": 90}, {"type": "side", "time": 4}, {"type": "setBPM", "time": 0, "bpm": 100}]"#),
+            ("Other/new.png", "y"),
+        ]);
+        let diff = diff_archives(&old, &new).unwrap();
+        assert_eq!(diff.added, vec!["new.png"]);
+        assert_eq!(diff.removed, vec!["old.png"]);
+        assert_eq!(diff.modified, vec!["easy.json", "level.json"]);
+        assert_eq!(diff.metadata.len(), 1);
+        assert_eq!(diff.metadata[0].field, "description");
+
+        let chart = &diff.charts[0];
+        assert_eq!(chart.variant, "Easy");
+        assert_eq!((chart.notes_added, chart.notes_removed, chart.notes_moved), (1, 1, 2));
+        assert_eq!(chart.shift, Some(0.5));
+        assert!(chart.tempo_changed);
+        assert_eq!(diff.summary(), DiffSummary { files: 4, metadata: 1, charts: 1 });
+    }
+
+    #[test]
+    fn identical_archives_have_no_changes() {
+        let archive = make_zip(&[
+            ("Song/level.json", &level("one")),
+            ("Song/easy.json", r#"[{"type": "block", "time": 1, "angle": 0}]"#),
+        ]);
+        assert_eq!(diff_archives(&archive, &archive).unwrap().summary(), DiffSummary::default());
+    }
+
+    #[test]
+    fn uneven_moves_have_no_shift() {
+        let old = make_zip(&[
+            ("Song/level.json", &level("one")),
+            ("Song/easy.json", r#"[{"type": "block", "time": 1, "angle": 0}, {"type": "block", "time": 2, "angle": 0}, {"type": "block", "time": 3, "angle": 0}]"#),
+        ]);
+        let new = make_zip(&[
+            ("Song/level.json", &level("one")),
+            ("Song/easy.json", r#"[{"type": "block", "time": 1, "angle": 0}, {"type": "block", "time": 2.5, "angle": 0}, {"type": "block", "time": 4, "angle": 0}]"#),
+        ]);
+        let chart = &diff_archives(&old, &new).unwrap().charts[0];
+        assert_eq!((chart.notes_added, chart.notes_removed, chart.notes_moved), (0, 0, 2));
+        assert_eq!(chart.shift, None);
+        assert!(!chart.tempo_changed);
+    }
+}
@@ -1,25 +1,39 @@
-use crate::api::upload::MAX_SIZE;
+use crate::api::upload::{MAX_PACK_LEVELS, MAX_SIZE};
 use crate::api::APIError;
 use crate::parsing::rar::RarArchiveReader;
+use crate::parsing::timeline::{count_notes, render_timeline};
 use crate::parsing::zip::ZipArchiveReader;
+use crate::util::LockResultExt;
 use ::zip::write::SimpleFileOptions;
-use ::zip::{ZipArchive, ZipWriter};
+use ::zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
-use std::io::{Cursor, Read, Write};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Component, PathBuf};
+use std::sync::{Arc, Mutex};
 
+pub mod diff;
 pub mod rar;
+pub mod timeline;
 pub mod zip;
 
 pub struct FileData {
+    // The level's own canonical archive
+    pub archive: Vec<u8>,
     pub level_data: LevelMetadata,
     pub image: Option<Vec<u8>>,
+    // Rendered timeline PNGs, in the same order as the level's variants
+    pub timelines: Vec<Option<Vec<u8>>>,
 }
 
 #[derive(Deserialize)]
 pub struct LevelData {
     pub metadata: LevelMetadata,
+    #[serde(default)]
+    pub events: Vec<Value>,
 }
 
 #[derive(Deserialize)]
@@ -42,7 +56,7 @@ pub struct LevelMetadata {
 
 #[derive(Deserialize)]
 pub struct BackgroundData {
-    image: String,
+    pub image: String,
     #[serde(rename = "cyanChannel")]
     pub cyan_channel: Option<ColorChannel>,
     #[serde(rename = "magentaChannel")]
@@ -73,10 +87,27 @@ impl Into<[u8; 3]> for &ColorChannel {
     }
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct LevelVariant {
-    display: String,
-    difficulty: f64,
+    pub display: String,
+    pub difficulty: f64,
+    #[serde(default, skip_serializing)]
+    chart: Option<String>,
+    #[serde(default)]
+    pub timeline: bool,
+    // Average of the players' votes, shown next to the charter's difficulty
+    #[serde(default)]
+    pub community_difficulty: Option<f64>,
+    #[serde(default)]
+    pub difficulty_votes: u64,
+    #[serde(default)]
+    pub difficulty_total: f64,
+}
+
+impl LevelVariant {
+    pub fn chart_file(&self) -> &str {
+        self.chart.as_deref().unwrap_or("chart.json")
+    }
 }
 
 impl Into<LevelVariant> for f64 {
@@ -84,6 +115,7 @@ impl Into<LevelVariant> for f64 {
         LevelVariant {
             display: get_difficulty(self),
             difficulty: self,
+            ..Default::default()
         }
     }
 }
@@ -123,7 +155,19 @@ pub fn get_parser<'a>(beatmap: &'a mut Vec<u8>) -> Result<Box<dyn ArchiveParser
     })
 }
 
-pub fn parse_archive(archive_parser: &mut dyn ArchiveParser) -> Result<FileData, Error> {
+/// Parses every level in the archive. Packs with several level folders are split up so each
+/// level gets its own canonical archive.
+pub fn parse_archive(archive_parser: &mut dyn ArchiveParser) -> Result<Vec<FileData>, Error> {
+    // Rars are converted to zips first so every level can be split out the same way
+    archive_parser.overwrite_file()?;
+    split_archive(archive_parser.get_file())?
+        .into_iter()
+        .map(parse_level)
+        .collect()
+}
+
+fn parse_level(mut archive: Vec<u8>) -> Result<FileData, Error> {
+    let archive_parser = ZipArchiveReader::new(&mut archive)?;
     let data = archive_parser
         .fetch_file("level.json")
         .and_then(|data| serde_json::from_slice::<LevelData>(&data).map_err(Error::new))
@@ -137,40 +181,206 @@ pub fn parse_archive(archive_parser: &mut dyn ArchiveParser) -> Result<FileData,
     let mut image = None;
     if let Some(bg_data) = metadata.bg_data.as_ref() {
         if !bg_data.image.is_empty() {
-            image = archive_parser.fetch_file(&bg_data.image).map_or(None, Some);
+            image = archive_parser.fetch_file(&bg_data.image).ok();
         }
     }
 
-    archive_parser.overwrite_file()?;
+    let charts = match metadata.difficulty {
+        Some(_) => vec!["chart.json"],
+        None => metadata.variants.iter().map(LevelVariant::chart_file).collect(),
+    };
+    let timelines = charts
+        .into_iter()
+        .map(|chart| {
+            archive_parser
+                .fetch_file(chart)
+                .and_then(|chart| render_timeline(&chart, &data.events))
+                .ok()
+        })
+        .collect();
+
+    check_archive(&mut archive, &get_folder_name(&metadata.song_name))?;
 
     Ok(FileData {
+        archive,
         level_data: metadata,
         image,
+        timelines,
     })
 }
 
-pub fn check_archive(file: &mut Vec<u8>) -> Result<(), Error> {
-    let output = Vec::new();
-    let mut zip = ZipWriter::new(Cursor::new(output));
+/// Splits a zip into one zip per folder holding a level, files outside every level are dropped.
+/// Archives with a single level are returned as they are.
+pub fn split_archive(file: &Vec<u8>) -> Result<Vec<Vec<u8>>, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(file))?;
+    let names: Vec<String> = archive.file_names().map(ToString::to_string).collect();
+    let mut roots: Vec<String> = names
+        .iter()
+        .map(|name| name.replace('\\', "/"))
+        .filter(|name| !is_junk(name) && is_level_file(name))
+        .map(|name| name[..name.rfind('/').map_or(0, |i| i + 1)].to_string())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    if roots.len() <= 1 {
+        return Ok(vec![file.clone()]);
+    }
+    // Checked before anything is split, so oversized packs are rejected before any work or writes
+    if roots.len() > MAX_PACK_LEVELS {
+        return Err(APIError::PackSizeError().into());
+    }
+
+    let mut levels: Vec<ZipWriter<Cursor<Vec<u8>>>> = roots
+        .iter()
+        .map(|_| ZipWriter::new(Cursor::new(Vec::new())))
+        .collect();
+    for name in names {
+        // Files belong to the deepest level folder they're in, in case levels are nested
+        let Some(level) = roots
+            .iter()
+            .enumerate()
+            .filter(|(_, root)| name.replace('\\', "/").starts_with(root.as_str()))
+            .max_by_key(|(_, root)| root.len())
+            .map(|(i, _)| i)
+        else {
+            continue;
+        };
+        levels[level].raw_copy_file(archive.by_name(&name)?)?;
+    }
+    levels
+        .into_iter()
+        .map(|level| Ok(level.finish()?.into_inner()))
+        .collect()
+}
+
+/// Combines canonical archives into one, each level keeps its own folder.
+pub fn merge_archives(archives: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let mut folders = HashSet::new();
+    let mut levels = vec![];
+    for archive in archives {
+        let names: Vec<String> = ZipArchive::new(Cursor::new(archive))?
+            .file_names()
+            .map(|name| name.replace('\\', "/"))
+            .collect();
+        let root = get_level_root(names.into_iter());
+        let name = match root.trim_end_matches('/') {
+            "" => "level",
+            root => root,
+        };
+        levels.push((get_unique_folder(name, &mut folders), archive.clone()));
+    }
+    bundle_archives(&levels)
+}
+
+/// The name with a number appended if another folder already took it, the result is reserved.
+pub fn get_unique_folder(name: &str, folders: &mut HashSet<String>) -> String {
+    let folder = (1..)
+        .map(|i| if i == 1 { name.to_string() } else { format!("{name} {i}") })
+        .find(|folder| !folders.contains(folder))
+        .unwrap();
+    folders.insert(folder.clone());
+    folder
+}
+
+/// Combines archives of different maps, each one's level is moved into the given folder.
+/// Entries are copied without recompressing them.
+pub fn bundle_archives(archives: &[(String, Vec<u8>)]) -> Result<Vec<u8>, Error> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (folder, archive) in archives {
+        bundle_archive(&mut zip, folder, archive)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Adds one archive's level to a bundle under the folder, see `bundle_archives`.
+pub fn bundle_archive<W: Write + Seek>(zip: &mut ZipWriter<W>, folder: &str, archive: &[u8]) -> Result<(), Error> {
+    let mut archive = ZipArchive::new(Cursor::new(archive))?;
+    let names: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !is_junk(name))
+        .map(ToString::to_string)
+        .collect();
+    let root = get_level_root(names.iter().map(|name| name.replace('\\', "/")));
+    for name in names {
+        let Some(relative) = name.replace('\\', "/").strip_prefix(&root).map(ToString::to_string) else {
+            continue;
+        };
+        zip.raw_copy_file_rename(archive.by_name(&name)?, format!("{folder}/{relative}"))?;
+    }
+    Ok(())
+}
+
+/// Output for a zip that's only ever appended to, which is all the zip writer needs when every
+/// entry is raw copied. Written bytes pile up in the shared buffer until they're taken out,
+/// so a bundle can be sent while it's being built.
+pub struct AppendWriter {
+    output: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+}
+
+impl AppendWriter {
+    pub fn new(output: Arc<Mutex<Vec<u8>>>) -> Self {
+        Self { output, position: 0 }
+    }
+}
+
+impl Write for AppendWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().ignore_poison().extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for AppendWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) | SeekFrom::End(0) => Ok(self.position),
+            SeekFrom::Start(position) if position == self.position => Ok(position),
+            _ => Err(io::Error::new(ErrorKind::Unsupported, "Written zip data can't be changed")),
+        }
+    }
+}
+
+/// Re-packs the archive so the same level always produces the same bytes: junk is stripped,
+/// the level's files are moved under a single `root` folder and entries are sorted with fixed
+/// timestamps and compression.
+pub fn check_archive(file: &mut Vec<u8>, root: &str) -> Result<(), Error> {
     let mut cursor: Cursor<&Vec<u8>> = Cursor::new(file);
     let mut archive = ZipArchive::new(&mut cursor)?;
-    let mut size = 0;
-    let files: Vec<String> = archive
+    let names: Vec<(String, String)> = archive
         .file_names()
-        .map(|string| string.to_string())
+        .map(|name| (name.to_string(), name.replace('\\', "/")))
+        .filter(|(_, name)| !name.ends_with('/') && !is_junk(name))
         .collect();
-    for file_name in files {
-        if !is_legal_name(&file_name)? {
+    // Everything is made relative to the folder holding the level, anything outside it is dropped
+    let level_root = get_level_root(names.iter().map(|(_, name)| name.clone()));
+
+    let mut files = BTreeMap::new();
+    for (original, name) in names {
+        if !is_legal_name(&name)? {
             continue;
         }
-        let mut file = archive.by_name(&file_name)?;
+        if let Some(relative) = name.strip_prefix(&level_root) {
+            files.entry(format!("{root}/{relative}")).or_insert(original);
+        }
+    }
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut size = 0;
+    for (file_name, original) in files {
+        let mut file = archive.by_name(&original)?;
         let file_size = file.size();
         // Prevent overflows
         if (size + file_size).max(file_size) > (MAX_SIZE * 2) as u64 {
             return Err(Error::msg("Uncompressed file size is too large!"));
         }
         size += file_size;
-        zip.start_file(file_name, SimpleFileOptions::default())?;
+        zip.start_file(file_name, get_canonical_options())?;
         let mut zip_file = Vec::new();
         file.read_to_end(&mut zip_file)?;
         zip.write_all(&zip_file)?;
@@ -179,9 +389,94 @@ pub fn check_archive(file: &mut Vec<u8>) -> Result<(), Error> {
     Ok(())
 }
 
+// The shallowest folder with a level file in it, with a trailing slash
+fn get_level_root(names: impl Iterator<Item = String>) -> String {
+    names
+        .filter(|name| is_level_file(name))
+        .min_by_key(|name| name.matches('/').count())
+        .map_or(String::new(), |name| name[..name.rfind('/').map_or(0, |i| i + 1)].to_string())
+}
+
+pub fn get_canonical_options() -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .compression_level(Some(9))
+        .last_modified_time(DateTime::default())
+        .unix_permissions(0o644)
+}
+
+/// Name of the single folder a level's files are stored under.
+pub fn get_folder_name(song: &str) -> String {
+    let name: String = song
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        "level".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+fn is_level_file(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let file_name = name.rsplit('/').next().unwrap_or_default();
+    file_name == "level.json" || file_name == "manifest.json"
+}
+
+// Files OSes leave behind that the game doesn't need
+const JUNK_FILES: [&'static str; 3] = [".ds_store", "thumbs.db", "desktop.ini"];
+
+fn is_junk(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let file_name = name.rsplit('/').next().unwrap_or_default();
+    name.split('/').any(|folder| folder == "__macosx")
+        || file_name.starts_with("._")
+        || JUNK_FILES.contains(&file_name)
+}
+
+pub struct ContentHashes {
+    pub charts: Vec<String>,
+    pub audio: Vec<String>,
+}
+
+pub const AUDIO_EXTENSIONS: [&'static str; 4] = ["mp3", "ogg", "oog", "wav"];
+// Shorter charts, like empty or template ones, are too generic to say two maps are the same
+pub const MIN_CHART_NOTES: usize = 20;
+
+/// SHA-256s of every chart and audio file in a checked archive, for spotting reuploads.
+pub fn get_content_hashes(file: &Vec<u8>) -> Result<ContentHashes, Error> {
+    let mut archive = ZipArchive::new(Cursor::new(file))?;
+    let mut hashes = ContentHashes {
+        charts: vec![],
+        audio: vec![],
+    };
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_ascii_lowercase();
+        let extension = name.rsplit('.').next().unwrap_or_default();
+        let is_audio = AUDIO_EXTENSIONS.contains(&extension);
+        if !is_audio && (extension != "json" || name.ends_with("level.json") || name.ends_with("manifest.json")) {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let output = match is_audio {
+            true => &mut hashes.audio,
+            false if count_notes(&data).is_ok_and(|notes| notes >= MIN_CHART_NOTES) => &mut hashes.charts,
+            false => continue,
+        };
+        output.push(format!("{:x}", Sha256::digest(&data)));
+    }
+    hashes.charts.sort();
+    hashes.audio.sort();
+    Ok(hashes)
+}
+
 // Allows misspelling, just here to block exes and other malicious files
-pub const EXTENSIONS: [&'static str; 12] = [
-    "png", "jpg", "jpeg", "webp", "mp3", "bmp", "ogg", "oog", "wav", "json", "md", "txt",
+pub const EXTENSIONS: [&'static str; 14] = [
+    "png", "jpg", "jpeg", "webp", "gif", "tga", "mp3", "bmp", "ogg", "oog", "wav", "json", "md", "txt",
 ];
 
 fn is_legal_name(name: &str) -> Result<bool, Error> {
@@ -195,4 +490,116 @@ pub trait ArchiveParser {
     fn fetch_file(&self, target_file_name: &str) -> Result<Vec<u8>, Error>;
 
     fn overwrite_file(&mut self) -> Result<(), Error>;
+
+    // The archive as a zip, only valid after overwrite_file
+    fn get_file(&self) -> &Vec<u8>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, get_canonical_options()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn file_names(archive: &Vec<u8>) -> Vec<String> {
+        let mut names: Vec<String> = ZipArchive::new(Cursor::new(archive)).unwrap()
+            .file_names()
+            .map(ToString::to_string)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn split_archive_separates_levels() {
+        let archive = make_zip(&[
+            ("pack/One/level.json", "1"),
+            ("pack/One/chart.json", "1"),
+            ("pack/Two/level.json", "2"),
+            ("pack/Two/song.ogg", "2"),
+            ("pack/readme.txt", ""),
+        ]);
+        let levels = split_archive(&archive).unwrap();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(file_names(&levels[0]), vec!["pack/One/chart.json", "pack/One/level.json"]);
+        assert_eq!(file_names(&levels[1]), vec!["pack/Two/level.json", "pack/Two/song.ogg"]);
+    }
+
+    #[test]
+    fn split_archive_rejects_large_packs() {
+        let names: Vec<String> = (0..=MAX_PACK_LEVELS).map(|i| format!("pack/{i}/level.json")).collect();
+        let archive = make_zip(&names.iter().map(|name| (name.as_str(), "")).collect::<Vec<_>>());
+        let err = split_archive(&archive).unwrap_err();
+        assert!(matches!(err.downcast::<APIError>(), Ok(APIError::PackSizeError())));
+        assert_eq!(split_archive(&make_zip(&[("pack/0/level.json", ""), ("pack/1/level.json", "")])).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn split_archive_keeps_single_levels() {
+        let archive = make_zip(&[("Song/level.json", ""), ("Song/chart.json", ""), ("readme.txt", "")]);
+        assert_eq!(split_archive(&archive).unwrap(), vec![archive]);
+    }
+
+    #[test]
+    fn merge_archives_keeps_levels_with_the_same_folder() {
+        let first = make_zip(&[("Song/level.json", "1"), ("Song/chart.json", "1")]);
+        let second = make_zip(&[("Song/level.json", "2"), ("Song/chart.json", "2")]);
+        let merged = merge_archives(&[first, second]).unwrap();
+        assert_eq!(
+            file_names(&merged),
+            vec!["Song 2/chart.json", "Song 2/level.json", "Song/chart.json", "Song/level.json"]
+        );
+        let mut merged = ZipArchive::new(Cursor::new(merged)).unwrap();
+        let mut level = String::new();
+        merged.by_name("Song 2/level.json").unwrap().read_to_string(&mut level).unwrap();
+        assert_eq!(level, "2");
+    }
+
+    #[test]
+    fn appended_bundle_matches_built_bundle() {
+        let first = make_zip(&[("One/level.json", "1"), ("One/chart.json", "1")]);
+        let second = make_zip(&[("pack/Two/level.json", "2")]);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut zip = ZipWriter::new(AppendWriter::new(output.clone()));
+        bundle_archive(&mut zip, "First", &first).unwrap();
+        let mut streamed = std::mem::take(&mut *output.lock().unwrap());
+        bundle_archive(&mut zip, "Second", &second).unwrap();
+        zip.finish().unwrap();
+        streamed.append(&mut output.lock().unwrap());
+
+        let built = bundle_archives(&[("First".to_string(), first), ("Second".to_string(), second)]).unwrap();
+        assert_eq!(streamed, built);
+        assert_eq!(file_names(&streamed), vec!["First/chart.json", "First/level.json", "Second/level.json"]);
+    }
+
+    #[test]
+    fn merged_archives_split_back_into_levels() {
+        let first = make_zip(&[("One/level.json", "1")]);
+        let second = make_zip(&[("Two/level.json", "2")]);
+        let merged = merge_archives(&[first, second]).unwrap();
+        assert_eq!(split_archive(&merged).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn only_charts_with_enough_notes_are_hashed() {
+        let notes = |count: usize| serde_json::to_string(&vec![serde_json::json!({"type": "block", "time": 1.0}); count]).unwrap();
+        let (full, short) = (notes(MIN_CHART_NOTES), notes(MIN_CHART_NOTES - 1));
+        let archive = make_zip(&[
+            ("Level/level.json", &notes(MIN_CHART_NOTES)),
+            ("Level/chart.json", &full),
+            ("Level/easy.json", &short),
+            ("Level/settings.json", "{}"),
+            ("Level/song.ogg", "audio"),
+        ]);
+        let hashes = get_content_hashes(&archive).unwrap();
+        assert_eq!(hashes.charts, vec![format!("{:x}", Sha256::digest(full.as_bytes()))]);
+        assert_eq!(hashes.audio.len(), 1);
+    }
 }
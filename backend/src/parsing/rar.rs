@@ -1,12 +1,11 @@
 use crate::api::upload::MAX_SIZE;
-use crate::parsing::ArchiveParser;
+use crate::parsing::{get_canonical_options, ArchiveParser};
 use anyhow::{Context, Error};
 use std::env::temp_dir;
 use std::io::{Cursor, Write};
 use std::path::PathBuf;
 use std::{fs, mem};
 use unrar::{Archive, CursorBeforeFile, CursorBeforeHeader, OpenArchive, Process};
-use zip::write::SimpleFileOptions;
 use zip::ZipWriter;
 
 pub struct RarArchiveReader<'a> {
@@ -51,7 +50,7 @@ impl ArchiveParser for RarArchiveReader<'_> {
             .to_string();
             let (file_data, next) = header.read()?;
             if !file_data.is_empty() {
-                zip.start_file(&file_name, SimpleFileOptions::default())?;
+                zip.start_file(&file_name, get_canonical_options())?;
                 zip.write(&file_data)?;
             }
             archive = next;
@@ -63,6 +62,10 @@ impl ArchiveParser for RarArchiveReader<'_> {
         }
         Ok(())
     }
+
+    fn get_file(&self) -> &Vec<u8> {
+        self.file
+    }
 }
 
 impl Drop for RarArchiveReader<'_> {
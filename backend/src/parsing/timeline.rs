@@ -0,0 +1,119 @@
+use anyhow::Error;
+use image::codecs::png::PngEncoder;
+use image::{ImageEncoder, PixelWithColorType, Rgb, RgbImage};
+use serde::Deserialize;
+use serde_json::Value;
+
+pub const TIMELINE_WIDTH: u32 = 512;
+pub const TIMELINE_HEIGHT: u32 = 48;
+// Height of the strip at the top used for tempo changes
+const TEMPO_HEIGHT: u32 = 8;
+
+const BACKGROUND: [u8; 3] = [24, 24, 32];
+const TEMPO: [u8; 3] = [255, 200, 40];
+
+#[derive(Deserialize)]
+struct ChartEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    time: f64,
+    #[serde(default)]
+    duration: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChartFile {
+    Events(Vec<Value>),
+    Level {
+        #[serde(default)]
+        events: Vec<Value>,
+    },
+}
+
+/// Renders a PNG of the chart's notes, holds and tempo changes over time.
+/// Tempo changes can live in either the chart or the level, so both are read.
+pub fn render_timeline(chart: &[u8], level_events: &[Value]) -> Result<Vec<u8>, Error> {
+    let chart = read_chart_events(chart)?;
+    // Skip anything we can't understand instead of failing the whole chart
+    let events: Vec<ChartEvent> = chart
+        .into_iter()
+        .chain(level_events.iter().cloned())
+        .filter_map(|event| serde_json::from_value(event).ok())
+        .filter(|event: &ChartEvent| event.time.is_finite() && event.time >= 0.0)
+        .collect();
+    if !events.iter().any(|event| is_note(&event.kind)) {
+        return Err(Error::msg("Chart has no notes"));
+    }
+
+    let length = events
+        .iter()
+        .map(|event| event.time + event.duration.max(0.0))
+        .fold(1.0, f64::max);
+    let to_x = |time: f64| ((time / length) * (TIMELINE_WIDTH - 1) as f64) as u32;
+
+    let mut image = RgbImage::from_pixel(TIMELINE_WIDTH, TIMELINE_HEIGHT, Rgb(BACKGROUND));
+    let note_middle = TEMPO_HEIGHT + (TIMELINE_HEIGHT - TEMPO_HEIGHT) / 2;
+    for event in &events {
+        let x = to_x(event.time);
+        if event.kind == "setBPM" {
+            fill(&mut image, (x, 0), (x, TEMPO_HEIGHT - 1), TEMPO);
+            continue;
+        }
+        let Some(color) = get_note_color(&event.kind) else {
+            continue;
+        };
+        if event.duration > 0.0 {
+            fill(&mut image, (x, note_middle), (to_x(event.time + event.duration), TIMELINE_HEIGHT - 4), color);
+        }
+        fill(&mut image, (x, TEMPO_HEIGHT + 2), (x, TIMELINE_HEIGHT - 2), color);
+    }
+
+    let mut output = Vec::new();
+    PngEncoder::new(&mut output).write_image(
+        image.as_ref(),
+        TIMELINE_WIDTH,
+        TIMELINE_HEIGHT,
+        <Rgb<u8> as PixelWithColorType>::COLOR_TYPE,
+    )?;
+    Ok(output)
+}
+
+/// Charts are either a list of events or an object holding one.
+pub fn read_chart_events(chart: &[u8]) -> Result<Vec<Value>, Error> {
+    Ok(match serde_json::from_slice::<ChartFile>(chart)? {
+        ChartFile::Events(events) | ChartFile::Level { events } => events,
+    })
+}
+
+/// How many notes the chart has, mines included.
+pub fn count_notes(chart: &[u8]) -> Result<usize, Error> {
+    Ok(read_chart_events(chart)?
+        .iter()
+        .filter(|event| event.get("type").and_then(Value::as_str).is_some_and(is_note))
+        .count())
+}
+
+pub fn is_note(kind: &str) -> bool {
+    get_note_color(kind).is_some()
+}
+
+fn get_note_color(kind: &str) -> Option<[u8; 3]> {
+    Some(match kind {
+        "block" | "extraTap" => [255, 255, 255],
+        "hold" => [120, 160, 255],
+        "inverse" => [255, 0, 255],
+        "side" => [0, 255, 255],
+        "mine" | "mineHold" => [255, 60, 60],
+        _ => return None,
+    })
+}
+
+fn fill(image: &mut RgbImage, start: (u32, u32), end: (u32, u32), color: [u8; 3]) {
+    for x in start.0..=end.0.min(image.width() - 1) {
+        for y in start.1..=end.1.min(image.height() - 1) {
+            image.put_pixel(x, y, Rgb(color));
+        }
+    }
+}
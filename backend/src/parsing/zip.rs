@@ -35,4 +35,8 @@ impl ArchiveParser for ZipArchiveReader<'_> {
     fn overwrite_file(&mut self) -> Result<(), Error> {
         Ok(())
     }
+
+    fn get_file(&self) -> &Vec<u8> {
+        self.file
+    }
 }
@@ -1,23 +1,81 @@
 use std::collections::HashMap;
+use std::time::Duration;
 use anyhow::Error;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::Region;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::delete_item::DeleteItemError;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
 use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{AttributeValue, DeleteRequest, KeysAndAttributes, PutRequest, ReturnValue, Select, TransactWriteItem, Update, WriteRequest};
 use aws_sdk_s3::config::BehaviorVersion;
+use aws_sdk_s3::presigning::PresigningConfig;
 use aws_sdk_s3::operation::delete_object::DeleteObjectOutput;
 use aws_sdk_s3::operation::put_object::PutObjectOutput;
 use serde::{Deserialize, Serialize};
 use serde_dynamo::Item;
 use crate::api::APIError;
+use crate::api::search::SortKey;
 use crate::util::database::{AccountLink, BeatMap};
 use crate::util::get_search_combos;
+use crate::util::warp::get_content_disposition;
 
 pub const BUCKET_NAME: &'static str = "beatmap-browser";
 pub const MAPS_TABLE_NAME: &'static str = "beatmapbrowser-maps";
 pub const USERS_TABLE_NAME: &'static str = "beatmapbrowser-users";
 pub const TOKENS_TABLE_NAME: &'static str = "beatmapbrowser-tokens";
+pub const FLAGS_TABLE_NAME: &'static str = "beatmapbrowser-flags";
+pub const ARCHIVES_TABLE_NAME: &'static str = "beatmapbrowser-archives";
+pub const HASHES_TABLE_NAME: &'static str = "beatmapbrowser-hashes";
+pub const EDITS_TABLE_NAME: &'static str = "beatmapbrowser-edits";
+pub const STATS_TABLE_NAME: &'static str = "beatmapbrowser-stats";
+pub const RATINGS_TABLE_NAME: &'static str = "beatmapbrowser-ratings";
+pub const COMMENTS_TABLE_NAME: &'static str = "beatmapbrowser-comments";
+pub const COLLECTIONS_TABLE_NAME: &'static str = "beatmapbrowser-collections";
+pub const NOTIFICATIONS_TABLE_NAME: &'static str = "beatmapbrowser-notifications";
+pub const FOLLOWS_TABLE_NAME: &'static str = "beatmapbrowser-follows";
+pub const FEED_TABLE_NAME: &'static str = "beatmapbrowser-feed";
+pub const DOWNLOADS_TABLE_NAME: &'static str = "beatmapbrowser-downloads";
 pub const BUCKET_REGION: &'static str = "us-east-2";
+// Long enough to finish a slow download, resumes get a new link
+pub const PRESIGNED_DURATION: Duration = Duration::from_secs(60 * 60);
+
+pub fn get_object_url(file_name: &str) -> String {
+    format!("https://{BUCKET_NAME}.s3.{BUCKET_REGION}.amazonaws.com/{file_name}")
+}
+
+/// Whether a write failed because its condition didn't hold, usually because the item changed first.
+pub fn is_condition_failure(err: &Error) -> bool {
+    err.downcast_ref::<SdkError<UpdateItemError>>()
+        .and_then(SdkError::as_service_error)
+        .is_some_and(UpdateItemError::is_conditional_check_failed_exception)
+        || err.downcast_ref::<SdkError<DeleteItemError>>()
+        .and_then(SdkError::as_service_error)
+        .is_some_and(DeleteItemError::is_conditional_check_failed_exception)
+        || err.downcast_ref::<SdkError<PutItemError>>()
+        .and_then(SdkError::as_service_error)
+        .is_some_and(PutItemError::is_conditional_check_failed_exception)
+}
+
+// Pages continue after the last item read, which is found on a date-sorted index by its date and id
+fn to_cursor(key: &HashMap<String, AttributeValue>) -> Option<String> {
+    Some(format!("{}_{}", key.get("date")?.as_s().ok()?, key.get("id")?.as_s().ok()?))
+}
+
+fn from_cursor(field: &str, value: &str, cursor: &str) -> Option<HashMap<String, AttributeValue>> {
+    let (date, id) = cursor.split_once('_')?;
+    Some(HashMap::from([
+        ("id".to_string(), AttributeValue::S(id.to_string())),
+        ("date".to_string(), AttributeValue::S(date.to_string())),
+        (field.to_string(), AttributeValue::S(value.to_string())),
+    ]))
+}
+
+pub fn to_attribute<T: Serialize>(value: &T) -> Result<AttributeValue, APIError> {
+    serde_dynamo::to_attribute_value(value).map_err(APIError::database_error)
+}
 
 #[derive(Clone)]
 pub struct Amazon {
@@ -61,6 +119,137 @@ impl Amazon {
         Ok(())
     }
 
+    /// Like `update`, but returns the whole item as it is after the update.
+    pub async fn update_returning<F: Fn(UpdateItemFluentBuilder) -> UpdateItemFluentBuilder>(&self,
+                                                                                             table_name: &'static str, id: String, updater: F) -> Result<HashMap<String, AttributeValue>, Error> {
+        Ok(updater(self.db_client
+            .update_item()
+            .table_name(table_name)
+            .key("id", AttributeValue::S(id))
+            .return_values(ReturnValue::AllNew))
+            .send()
+            .await?
+            .attributes
+            .unwrap_or_default())
+    }
+
+    pub async fn increment(&self, table_name: &'static str, id: String, field: &str) -> Result<(), Error> {
+        self.update(table_name, id, |builder| {
+            builder
+                .update_expression("SET #field = if_not_exists(#field, :start) + :inc")
+                .expression_attribute_names("#field", field)
+                .expression_attribute_values(":start", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":inc", AttributeValue::N("1".to_string()))
+        }).await
+    }
+
+    /// Applies every update or none of them, returns false if one of their conditions failed.
+    pub async fn transact(&self, updates: Vec<Update>) -> Result<bool, Error> {
+        let result = self.db_client
+            .transact_write_items()
+            .set_transact_items(Some(updates
+                .into_iter()
+                .map(|update| TransactWriteItem::builder().update(update).build())
+                .collect()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|err| match err {
+                TransactWriteItemsError::TransactionCanceledException(cancelled) => cancelled
+                    .cancellation_reasons()
+                    .iter()
+                    .any(|reason| reason.code() == Some("ConditionalCheckFailed")),
+                _ => false,
+            }) => Ok(false),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    pub async fn set_fields(&self, table_name: &'static str, id: String,
+                            fields: Vec<(&str, AttributeValue)>) -> Result<(), Error> {
+        let expression = fields.iter()
+            .map(|(field, _)| format!("#{field} = :{field}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.update(table_name, id, |builder| {
+            fields.iter().fold(builder.update_expression(format!("SET {expression}")), |builder, (field, value)| {
+                builder
+                    .expression_attribute_names(format!("#{field}"), field.to_string())
+                    .expression_attribute_values(format!(":{field}"), value.clone())
+            })
+        }).await
+    }
+
+    /// Like `set_fields`, but only if the condition holds.
+    pub async fn set_fields_if(&self, table_name: &'static str, id: String, fields: Vec<(&str, AttributeValue)>,
+                               condition: &str, values: Vec<(&str, AttributeValue)>) -> Result<(), Error> {
+        let expression = fields.iter()
+            .map(|(field, _)| format!("#{field} = :{field}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.update(table_name, id, |builder| {
+            let builder = builder.update_expression(format!("SET {expression}")).condition_expression(condition);
+            let builder = fields.iter().fold(builder, |builder, (field, value)| {
+                builder
+                    .expression_attribute_names(format!("#{field}"), field.to_string())
+                    .expression_attribute_values(format!(":{field}"), value.clone())
+            });
+            values.iter().fold(builder, |builder, (name, value)| builder.expression_attribute_values(*name, value.clone()))
+        }).await
+    }
+
+    pub async fn get_object(&self, file_name: &str) -> Result<Vec<u8>, Error> {
+        Ok(self.s3_client
+            .get_object()
+            .bucket(BUCKET_NAME)
+            .key(file_name)
+            .send()
+            .await?
+            .body
+            .collect()
+            .await?
+            .to_vec())
+    }
+
+    /// A temporary link to the object that downloads it as `download_name`.
+    pub async fn presign_object(&self, file_name: &str, download_name: &str) -> Result<String, Error> {
+        let request = self.s3_client
+            .get_object()
+            .bucket(BUCKET_NAME)
+            .key(file_name)
+            .response_content_disposition(get_content_disposition(download_name))
+            .presigned(PresigningConfig::expires_in(PRESIGNED_DURATION)?)
+            .await?;
+        Ok(request.uri().to_string())
+    }
+
+    pub async fn get_object_size(&self, file_name: &str) -> Result<Option<i64>, Error> {
+        match self.s3_client
+            .head_object()
+            .bucket(BUCKET_NAME)
+            .key(file_name)
+            .send()
+            .await {
+            Ok(object) => Ok(object.content_length),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(None),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    pub async fn object_exists(&self, file_name: &str) -> Result<bool, Error> {
+        match self.s3_client
+            .head_object()
+            .bucket(BUCKET_NAME)
+            .key(file_name)
+            .send()
+            .await {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(false),
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
     pub async fn delete_object(
         &self,
         file_name: &str,
@@ -98,9 +287,77 @@ impl Amazon {
         Ok(())
     }
 
+    /// Like `upload` without extra fields, but only if the condition holds.
+    pub async fn upload_if<T: Serialize>(&self, table: &str, data: &T, condition: &str,
+                                         values: Vec<(&str, AttributeValue)>) -> Result<(), Error> {
+        let item: HashMap<String, AttributeValue> = serde_dynamo::to_item(data)?;
+        values.into_iter()
+            .fold(self.db_client
+                .put_item()
+                .table_name(table)
+                .set_item(Some(item))
+                .condition_expression(condition), |builder, (name, value)| builder.expression_attribute_values(name, value))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Like `upload` without extra fields, but returns the item it replaced.
+    pub async fn replace<T: Serialize + for<'a> Deserialize<'a>>(&self, table: &str, data: &T) -> Result<Option<T>, Error> {
+        let item: HashMap<String, AttributeValue> = serde_dynamo::to_item(data)?;
+        Ok(self.db_client
+            .put_item()
+            .table_name(table)
+            .set_item(Some(item))
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await?
+            .attributes
+            .map(serde_dynamo::from_item)
+            .transpose()?)
+    }
+
+    /// Puts the items in batches, which can't have conditions.
+    pub async fn upload_all<T: Serialize>(&self, table: &str, data: &[T]) -> Result<(), Error> {
+        let requests = data.iter()
+            .map(|item| Ok(WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(serde_dynamo::to_item(item)?)).build()?)
+                .build()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.write_all(table, requests).await
+    }
+
+    /// Deletes the items with these keys in batches.
+    pub async fn remove_all(&self, table: &str, ids: Vec<String>) -> Result<(), Error> {
+        let requests = ids.into_iter()
+            .map(|id| Ok(WriteRequest::builder()
+                .delete_request(DeleteRequest::builder().key("id", AttributeValue::S(id)).build()?)
+                .build()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.write_all(table, requests).await
+    }
+
+    async fn write_all(&self, table: &str, requests: Vec<WriteRequest>) -> Result<(), Error> {
+        // BatchWriteItem takes at most 25 writes per request
+        for chunk in requests.chunks(25) {
+            let mut writing = Some(chunk.to_vec());
+            // Writes DynamoDB didn't get to are returned to be sent again
+            while let Some(requests) = writing.take() {
+                let output = self.db_client
+                    .batch_write_item()
+                    .request_items(table, requests)
+                    .send()
+                    .await?;
+                writing = output.unprocessed_items.unwrap_or_default().remove(table).filter(|requests| !requests.is_empty());
+            }
+        }
+        Ok(())
+    }
+
     pub async fn search_songs(
         &self,
         query: &str,
+        sort: SortKey,
     ) -> Result<Vec<BeatMap>, Error> {
         let trying = query.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty())
             .take(3);
@@ -123,7 +380,12 @@ impl Amazon {
             }
         }
         let mut values: Vec<_> = found.into_values().collect();
-        values.sort_by_key(|(map, count)| (*count * 1000000000) + map.upvotes);
+        values.sort_by(|(first, first_count), (second, second_count)| {
+            first_count.cmp(second_count).then_with(|| match sort {
+                SortKey::Upvotes => first.upvotes.cmp(&second.upvotes),
+                SortKey::Score => first.score.total_cmp(&second.score),
+            })
+        });
         values.reverse();
         Ok(values.into_iter().map(|(map, _)| map).collect())
     }
@@ -155,6 +417,52 @@ impl Amazon {
             .transpose()?)
     }
 
+    /// Reads the item by its key, with strong consistency unlike queries on an index.
+    pub async fn get_item<T: for<'a> Deserialize<'a>>(
+        &self,
+        table: &'static str,
+        id: String,
+    ) -> Result<Option<T>, Error> {
+        Ok(self.db_client
+            .get_item()
+            .table_name(table)
+            .key("id", AttributeValue::S(id))
+            .consistent_read(true)
+            .send()
+            .await?
+            .item
+            .map(serde_dynamo::from_item)
+            .transpose()?)
+    }
+
+    /// Reads the items with these keys in batches, missing items are left out and the order isn't kept.
+    pub async fn get_items<T: for<'a> Deserialize<'a>>(
+        &self,
+        table: &'static str,
+        ids: Vec<String>,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = vec![];
+        // BatchGetItem takes at most 100 keys per request
+        for chunk in ids.chunks(100) {
+            let mut keys = Some(KeysAndAttributes::builder()
+                .set_keys(Some(chunk.iter().map(|id| HashMap::from([("id".to_string(), AttributeValue::S(id.clone()))])).collect()))
+                .build()?);
+            // Keys DynamoDB didn't get to are returned to be requested again
+            while let Some(requesting) = keys.take() {
+                let output = self.db_client
+                    .batch_get_item()
+                    .request_items(table, requesting)
+                    .send()
+                    .await?;
+                for item in output.responses.unwrap_or_default().remove(table).unwrap_or_default() {
+                    items.push(serde_dynamo::from_item(item)?);
+                }
+                keys = output.unprocessed_keys.unwrap_or_default().remove(table).filter(|keys| !keys.keys.is_empty());
+            }
+        }
+        Ok(items)
+    }
+
     pub async fn query_one<T: for<'a> Deserialize<'a>>(
         &self,
         table: &'static str,
@@ -186,6 +494,134 @@ impl Amazon {
             .collect::<Result<Vec<T>, serde_dynamo::Error>>()?)
     }
 
+    /// A page of the items on the index, newest first by its `date` sort key, and the cursor to the next page.
+    /// The filter is applied after the limit, so a page can come back short while there are more.
+    pub async fn query_page<T: for<'a> Deserialize<'a>>(
+        &self,
+        table: &'static str,
+        field: &str,
+        value: String,
+        filter: Option<(&str, AttributeValue)>,
+        cursor: Option<String>,
+        limit: i32,
+    ) -> Result<(Vec<T>, Option<String>), APIError> {
+        let start = cursor
+            .map(|cursor| from_cursor(field, &value, &cursor).ok_or(APIError::ArgumentError()))
+            .transpose()?;
+        let mut query = self.db_client
+            .query()
+            .table_name(table)
+            .index_name(format!("{}-index", field))
+            .key_condition_expression("#field = :input")
+            .expression_attribute_names("#field", field)
+            .expression_attribute_values(":input", AttributeValue::S(value))
+            .scan_index_forward(false)
+            .limit(limit)
+            .set_exclusive_start_key(start);
+        if let Some((name, filtering)) = filter {
+            query = query
+                .filter_expression("#filter = :filter")
+                .expression_attribute_names("#filter", name)
+                .expression_attribute_values(":filter", filtering);
+        }
+        let output = query.send().await.map_err(APIError::database_error)?;
+        let items = output.items
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| serde_dynamo::from_item(item))
+            .collect::<Result<Vec<T>, serde_dynamo::Error>>()
+            .map_err(APIError::database_error)?;
+        Ok((items, output.last_evaluated_key.as_ref().and_then(to_cursor)))
+    }
+
+    /// How many items on the index are newer than `after` and match the filter, without reading them.
+    pub async fn count(
+        &self,
+        table: &'static str,
+        field: &str,
+        value: String,
+        after: Option<AttributeValue>,
+        filter: Option<(&str, AttributeValue)>,
+    ) -> Result<usize, Error> {
+        let mut query = self.db_client
+            .query()
+            .table_name(table)
+            .index_name(format!("{}-index", field))
+            .key_condition_expression("#field = :input")
+            .expression_attribute_names("#field", field)
+            .expression_attribute_values(":input", AttributeValue::S(value))
+            .select(Select::Count);
+        if let Some(after) = after {
+            query = query
+                .key_condition_expression("#field = :input AND #date > :after")
+                .expression_attribute_names("#date", "date")
+                .expression_attribute_values(":after", after);
+        }
+        if let Some((name, filtering)) = filter {
+            query = query
+                .filter_expression("#filter = :filter")
+                .expression_attribute_names("#filter", name)
+                .expression_attribute_values(":filter", filtering);
+        }
+        let pages: Vec<_> = query.into_paginator().send().try_collect().await?;
+        Ok(pages.iter().map(|page| page.count as usize).sum())
+    }
+
+    pub async fn scan<T: for<'a> Deserialize<'a>>(
+        &self,
+        table: &'static str,
+    ) -> Result<Vec<T>, Error> {
+        Ok(self.db_client
+            .scan()
+            .table_name(table)
+            .into_paginator()
+            .items()
+            .send()
+            .try_collect()
+            .await?
+            .into_iter()
+            .map(|item| serde_dynamo::from_item(item))
+            .collect::<Result<Vec<T>, serde_dynamo::Error>>()?)
+    }
+
+    /// Like [`Amazon::scan`], but sees every write finished before it started.
+    pub async fn scan_consistent<T: for<'a> Deserialize<'a>>(
+        &self,
+        table: &'static str,
+    ) -> Result<Vec<T>, Error> {
+        Ok(self.db_client
+            .scan()
+            .table_name(table)
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send()
+            .try_collect()
+            .await?
+            .into_iter()
+            .map(|item| serde_dynamo::from_item(item))
+            .collect::<Result<Vec<T>, serde_dynamo::Error>>()?)
+    }
+
+    /// Deletes the item only if the condition holds.
+    pub async fn remove_if(
+        &self,
+        table: &'static str,
+        id: String,
+        condition: &str,
+        values: Vec<(&str, AttributeValue)>,
+    ) -> Result<(), Error> {
+        values.into_iter()
+            .fold(self.db_client
+                .delete_item()
+                .table_name(table)
+                .key("id", AttributeValue::S(id))
+                .condition_expression(condition), |builder, (name, value)| builder.expression_attribute_values(name, value))
+            .send()
+            .await?;
+        Ok(())
+    }
+
     pub async fn remove(
         &self,
         table: &'static str,
@@ -220,4 +656,35 @@ impl Amazon {
         })
             .await.map_err(APIError::database_error)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_continues_after_the_last_item() {
+        let last = HashMap::from([
+            ("id".to_string(), AttributeValue::S("user_map_2".to_string())),
+            ("date".to_string(), AttributeValue::S("2024-05-01T10:00:00.5Z".to_string())),
+            ("user_id".to_string(), AttributeValue::S("user".to_string())),
+        ]);
+        let cursor = to_cursor(&last).unwrap();
+        assert_eq!(from_cursor("user_id", "user", &cursor), Some(last));
+    }
+
+    #[test]
+    fn cursor_only_reads_the_callers_index() {
+        let cursor = to_cursor(&HashMap::from([
+            ("id".to_string(), AttributeValue::S("someone_map_2".to_string())),
+            ("date".to_string(), AttributeValue::S("2024-05-01T10:00:00Z".to_string())),
+        ])).unwrap();
+        let start = from_cursor("user_id", "user", &cursor).unwrap();
+        assert_eq!(start["user_id"], AttributeValue::S("user".to_string()));
+        assert_eq!(from_cursor("user_id", "user", "not a cursor"), None);
+    }
+
+    #[test]
+    fn no_cursor_without_a_sort_key() {
+        assert_eq!(to_cursor(&HashMap::from([("id".to_string(), AttributeValue::S("user_map_2".to_string()))])), None);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::api::APIError;
+use crate::util::amazon::{is_condition_failure, to_attribute, ARCHIVES_TABLE_NAME, MAPS_TABLE_NAME};
+use crate::util::data;
+use crate::util::database::{get_archive_key, get_hashed_archive_key, BeatMap};
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::sleep;
+use anyhow::Error;
+
+// Archives are shared by hash and counted in the archives table, a map counts once however many
+// of its versions use the archive. Deleting one first marks it, so an upload that wants it back
+// waits until the object is gone and then stores it again.
+const MAX_ACQUIRE_ATTEMPTS: u32 = 20;
+const ACQUIRE_RETRY_DELAY: Duration = Duration::from_millis(100);
+// Deletions that take longer than this crashed, uploads can take the archive over
+const STALE_DELETION_SECONDS: i64 = 60;
+
+/// A reference counted by `acquire_archive` that's released again when dropped, unless it's kept.
+/// Uploads can be cancelled by their timeout at any await, so dropping is the one place that always runs.
+pub struct AcquiredArchive {
+    hash: Option<String>,
+}
+
+impl AcquiredArchive {
+    /// The saved map counts for the archive now.
+    pub fn keep(mut self) {
+        self.hash = None;
+    }
+}
+
+impl Drop for AcquiredArchive {
+    fn drop(&mut self) {
+        if let Some(hash) = self.hash.take() {
+            tokio::spawn(async move {
+                if let Err(err) = release_hash(&hash).await {
+                    println!("Failed to release archive {hash}: {err:?}");
+                }
+            });
+        }
+    }
+}
+
+/// Counts a new map using the archive, storing it if nothing else does.
+pub async fn acquire_archive(hash: &str, archive: Vec<u8>) -> Result<AcquiredArchive, APIError> {
+    let key = get_hashed_archive_key(hash);
+    for _ in 0..MAX_ACQUIRE_ATTEMPTS {
+        let result = data().await.amazon
+            .update_returning(ARCHIVES_TABLE_NAME, hash.to_string(), |builder| {
+                builder
+                    .update_expression("ADD #references :one REMOVE deleting")
+                    .condition_expression("attribute_not_exists(deleting) OR deleting < :stale")
+                    .expression_attribute_names("#references", "references")
+                    .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+                    .expression_attribute_values(":stale", AttributeValue::N(
+                        (Utc::now().timestamp() - STALE_DELETION_SECONDS).to_string(),
+                    ))
+            })
+            .await;
+        let item = match result {
+            Ok(item) => item,
+            Err(err) if is_condition_failure(&err) => {
+                sleep(ACQUIRE_RETRY_DELAY).await;
+                continue;
+            }
+            Err(err) => return Err(APIError::database_error(err)),
+        };
+        // Archives from before counting may be missing their object
+        if get_references(&item) == 1
+            || !data().await.amazon.object_exists(&key).await.map_err(APIError::database_error)?
+        {
+            data().await.amazon
+                .upload_object(archive, key.as_str())
+                .await
+                .map_err(APIError::database_error)?;
+        }
+        return Ok(AcquiredArchive { hash: Some(hash.to_string()) });
+    }
+    Err(APIError::database_error(Error::msg(format!("Archive {hash} is stuck being deleted"))))
+}
+
+/// Deletes the map's archives, except ones other maps still use.
+pub async fn release_archive(map: &BeatMap) -> Result<(), APIError> {
+    for hash in map.get_archive_hashes() {
+        release_hash(&hash).await?;
+    }
+    // Archives from before hashing belong to only this map
+    data().await.amazon
+        .delete_object(get_archive_key(&map.id, &None).as_str())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(())
+}
+
+async fn release_hash(hash: &str) -> Result<(), APIError> {
+    let result = data().await.amazon
+        .update_returning(ARCHIVES_TABLE_NAME, hash.to_string(), |builder| {
+            builder
+                .update_expression("ADD #references :minus")
+                .condition_expression("#references > :zero")
+                .expression_attribute_names("#references", "references")
+                .expression_attribute_values(":minus", AttributeValue::N("-1".to_string()))
+                .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+        })
+        .await;
+    let item = match result {
+        Ok(item) => item,
+        // Not counted yet, so it's kept until migrate_archives counts it
+        Err(err) if is_condition_failure(&err) => return Ok(()),
+        Err(err) => return Err(APIError::database_error(err)),
+    };
+    if get_references(&item) > 0 {
+        return Ok(());
+    }
+
+    // Fails if an upload started using it again in the meantime
+    let claimed = data().await.amazon
+        .update(ARCHIVES_TABLE_NAME, hash.to_string(), |builder| {
+            builder
+                .update_expression("SET deleting = :now")
+                .condition_expression("#references = :zero AND attribute_not_exists(deleting)")
+                .expression_attribute_names("#references", "references")
+                .expression_attribute_values(":now", AttributeValue::N(Utc::now().timestamp().to_string()))
+                .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+        })
+        .await;
+    match claimed {
+        Ok(()) => {}
+        Err(err) if is_condition_failure(&err) => return Ok(()),
+        Err(err) => return Err(APIError::database_error(err)),
+    }
+    data().await.amazon
+        .delete_object(get_hashed_archive_key(hash).as_str())
+        .await
+        .map_err(APIError::database_error)?;
+    data().await.amazon
+        .remove_if(ARCHIVES_TABLE_NAME, hash.to_string(), "attribute_exists(deleting)", vec![])
+        .await
+        .or_else(|err| match is_condition_failure(&err) {
+            true => Ok(()),
+            false => Err(APIError::database_error(err)),
+        })
+}
+
+/// Hashes the archives of maps from before hashing and counts them like uploads do, returning how
+/// many maps were moved. Archives uploaded since hashing were counted then, and moved maps aren't
+/// picked up again, so running it twice counts nothing twice.
+/// The old `{id}.zip` objects stay for clients that still link to them directly.
+pub async fn migrate_archives() -> Result<usize, APIError> {
+    let maps: Vec<BeatMap> = data().await.amazon.scan(MAPS_TABLE_NAME).await
+        .map_err(APIError::database_error)?;
+    let mut migrated = 0;
+    for mut map in maps {
+        if map.archive_hash.is_some() && map.versions.iter().all(|version| version.archive_hash.is_some()) {
+            continue;
+        }
+        match hash_legacy_archive(&mut map).await {
+            Ok(()) => migrated += 1,
+            Err(err) => println!("Failed to migrate the archive of {}: {err:?}", map.id),
+        }
+    }
+    Ok(migrated)
+}
+
+// Copies `{id}.zip` to its hashed key, the versions that used it point to the copy afterwards.
+// It's counted before the map points to it, so a crash in between only keeps it around longer
+async fn hash_legacy_archive(map: &mut BeatMap) -> Result<(), APIError> {
+    let archive = data().await.amazon.get_object(get_archive_key(&map.id, &None).as_str()).await
+        .map_err(APIError::database_error)?;
+    let hash = format!("{:x}", Sha256::digest(&archive));
+    // Versions uploaded since hashing already count for their archives
+    if !map.get_archive_hashes().contains(&hash) {
+        acquire_archive(&hash, archive).await?.keep();
+    }
+
+    // Only the first version of a map updated since hashing can be the old archive
+    if map.versions.is_empty() {
+        map.versions = map.get_versions();
+    }
+    for version in map.versions.iter_mut().filter(|version| version.archive_hash.is_none()) {
+        version.archive_hash = Some(hash.clone());
+    }
+    map.archive_hash.get_or_insert(hash);
+    let archive_hash = to_attribute(&map.archive_hash)?;
+    let versions = to_attribute(&map.versions)?;
+    data().await.amazon
+        .update(MAPS_TABLE_NAME, map.id.to_string(), |builder| {
+            builder
+                .update_expression("SET archive_hash = :hash, versions = :versions")
+                .condition_expression("attribute_exists(id)")
+                .expression_attribute_values(":hash", archive_hash.clone())
+                .expression_attribute_values(":versions", versions.clone())
+        })
+        .await
+        .map_err(APIError::database_error)
+}
+
+fn get_references(item: &HashMap<String, AttributeValue>) -> i64 {
+    item.get("references")
+        .and_then(|value| value.as_n().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
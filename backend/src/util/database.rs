@@ -1,12 +1,23 @@
+use crate::parsing::diff::{DiffSummary, FieldChange};
 use crate::parsing::LevelVariant;
-use chrono::{DateTime, Utc};
+use crate::util::image::ImageSize;
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 pub type UserID = Uuid;
 pub type MapID = Uuid;
+pub type PackID = Uuid;
+pub type CommentID = Uuid;
+pub type CollectionID = Uuid;
+pub type NotificationID = String;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+pub fn get_pack_key(pack_id: &PackID) -> String {
+    format!("packs/{pack_id}.zip")
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct BeatMap {
     pub song: String,
     pub artist: String,
@@ -16,17 +27,285 @@ pub struct BeatMap {
     pub description: String,
     pub artist_list: String,
     pub image: bool,
+    #[serde(default)]
+    pub image_sizes: Vec<ImageSize>,
+    #[serde(default)]
+    pub share_card: bool,
+    #[serde(default)]
+    pub palette: Vec<String>,
+    #[serde(default)]
+    pub image_hash: Option<u64>,
+    #[serde(default)]
+    pub chart_hashes: Vec<String>,
+    #[serde(default)]
+    pub audio_hashes: Vec<String>,
+    // SHA-256 of the stored archive, None for maps uploaded before archives were deduplicated
+    #[serde(default)]
+    pub archive_hash: Option<String>,
+    // Set for levels uploaded together in one archive, which can be downloaded as a whole
+    #[serde(default)]
+    pub pack_id: Option<PackID>,
+    // Every uploaded archive oldest first, empty for maps uploaded before versions were kept
+    #[serde(default)]
+    pub versions: Vec<MapVersion>,
     pub upvotes: u64,
+    #[serde(default)]
+    pub downvotes: u64,
+    // Wilson lower bound of the votes, kept up to date so search can sort by it
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default)]
+    pub downloads: u64,
+    // Average star rating out of 5
+    #[serde(default)]
+    pub rating: Option<f64>,
+    #[serde(default)]
+    pub ratings: u64,
+    #[serde(default)]
+    pub rating_total: u64,
+    // Bumped whenever the rating totals or the variants holding them change, writes computed
+    // from an earlier read only go through if it's unchanged
+    #[serde(default)]
+    pub rating_version: u64,
     pub upload_date: DateTime<Utc>,
     pub update_date: DateTime<Utc>,
     pub id: MapID,
 }
 
+impl BeatMap {
+    pub fn get_archive_key(&self) -> String {
+        get_archive_key(&self.id, &self.archive_hash)
+    }
+
+    /// What the archive is saved as when downloaded, like `Artist - Song (Charter).zip`.
+    pub fn get_download_name(&self) -> String {
+        let name = format!("{} - {} ({}).zip", self.artist, self.song, self.charter);
+        name.chars()
+            .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | '"' | ':' | '*' | '?' | '<' | '>' | '|'))
+            .collect()
+    }
+
+    /// The map's versions, maps from before versioning only have their current archive.
+    pub fn get_versions(&self) -> Vec<MapVersion> {
+        if !self.versions.is_empty() {
+            return self.versions.clone();
+        }
+        vec![MapVersion {
+            version: 1,
+            archive_hash: self.archive_hash.clone(),
+            changelog: String::new(),
+            upload_date: self.upload_date,
+            changes: None,
+        }]
+    }
+
+    /// The hashes of every stored archive this map needs, archives from before hashing have none.
+    pub fn get_archive_hashes(&self) -> Vec<String> {
+        let mut hashes = vec![];
+        for hash in self.archive_hash.iter().chain(self.versions.iter().filter_map(|version| version.archive_hash.as_ref())) {
+            if !hashes.contains(hash) {
+                hashes.push(hash.clone());
+            }
+        }
+        hashes
+    }
+}
+
+pub fn get_archive_key(map_id: &MapID, archive_hash: &Option<String>) -> String {
+    match archive_hash {
+        Some(hash) => get_hashed_archive_key(hash),
+        None => format!("{map_id}.zip"),
+    }
+}
+
+pub fn get_hashed_archive_key(hash: &str) -> String {
+    format!("archives/{hash}.zip")
+}
+
+pub fn get_diff_key(map_id: &MapID, version: u32) -> String {
+    format!("diffs/{map_id}_{version}.json")
+}
+
+pub fn get_timeline_key(map_id: &MapID, variant: usize) -> String {
+    format!("{map_id}_timeline_{variant}.png")
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapVersion {
+    pub version: u32,
+    pub archive_hash: Option<String>,
+    pub changelog: String,
+    pub upload_date: DateTime<Utc>,
+    // How much changed since the previous version, the full diff is at `get_diff_key`
+    #[serde(default)]
+    pub changes: Option<DiffSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateFlag {
+    pub id: Uuid,
+    pub map_id: MapID,
+    pub original_id: MapID,
+    pub reason: String,
+    pub flag_date: DateTime<Utc>,
+}
+
+// Indexes maps by their chart, audio and background hashes to find reuploads, the id is `{hash}_{map_id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContentHash {
+    pub id: String,
+    pub hash: String,
+    pub map_id: MapID,
+    // The map's background hash, so background matches are narrowed without reading the maps
+    #[serde(default)]
+    pub image_hash: Option<u64>,
+}
+
+// One map's activity on one day, the id is `{map_id}_{day}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DailyStats {
+    pub id: String,
+    pub map_id: MapID,
+    pub charter_uid: UserID,
+    pub day: NaiveDate,
+    #[serde(default)]
+    pub downloads: u64,
+    // Unvotes take away from the day they happen on, so this can go negative
+    #[serde(default)]
+    pub upvotes: i64,
+    #[serde(default)]
+    pub downvotes: i64,
+}
+
+// One user's votes on a map, the id is `{map_id}_{user_id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rating {
+    pub id: String,
+    pub map_id: MapID,
+    pub user_id: UserID,
+    pub rating: Option<u8>,
+    // Perceived difficulty by variant display name
+    #[serde(default)]
+    pub difficulties: HashMap<String, f64>,
+    pub rating_date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: CommentID,
+    pub map_id: MapID,
+    pub author: UserID,
+    pub parent: Option<CommentID>,
+    // How many replies up the thread goes, top level comments are 0
+    #[serde(default)]
+    pub depth: u32,
+    // Replies that haven't been removed, a comment is only blanked while it has some
+    #[serde(default)]
+    pub replies: u64,
+    pub text: String,
+    pub post_date: DateTime<Utc>,
+    pub edit_date: Option<DateTime<Utc>>,
+    // Deleted comments with replies are kept with their text removed
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+// One user's download of a map, the id is `{map_id}_{user_id}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Download {
+    pub id: String,
+    pub map_id: MapID,
+    pub user_id: UserID,
+}
+
+// A charter one user follows, the id is `{charter}_{follower}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Follow {
+    pub id: String,
+    pub charter: UserID,
+    pub follower: UserID,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedKind {
+    Upload,
+    Update,
+}
+
+// A new map or version in one user's feed, the id is `{user_id}_{map_id}_{version}`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeedEvent {
+    pub id: String,
+    pub user_id: UserID,
+    pub map_id: MapID,
+    pub kind: FeedKind,
+    // Set for updates
+    pub version: Option<u32>,
+    #[serde(with = "sortable_date")]
+    pub date: DateTime<Utc>,
+}
+
+// Upvotes use `upvote_{map_id}_{voter}` as the id, so voting again doesn't notify twice
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Notification {
+    pub id: NotificationID,
+    pub user_id: UserID,
+    pub map_id: MapID,
+    // Kept so the notification still makes sense after the map is deleted
+    pub song: String,
+    pub event: NotificationEvent,
+    #[serde(with = "sortable_date")]
+    pub date: DateTime<Utc>,
+    pub read: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotificationEvent {
+    // A followed charter uploaded a new map
+    Upload,
+    // A downloaded map got a new version
+    Update { version: u32 },
+    // The user's map was upvoted
+    Upvote,
+    // A downloaded or owned map was deleted
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: CollectionID,
+    pub owner: UserID,
+    pub name: String,
+    pub public: bool,
+    // In the order the owner chose
+    pub maps: Vec<MapID>,
+    pub create_date: DateTime<Utc>,
+    pub update_date: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MapEdit {
+    pub id: Uuid,
+    pub map_id: MapID,
+    pub editor: UserID,
+    pub changes: Vec<FieldChange>,
+    pub edit_date: DateTime<Utc>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct User {
     pub maps: Vec<MapID>,
     pub downloaded: Vec<MapID>,
     pub upvoted: Vec<MapID>,
+    #[serde(default)]
+    pub downvoted: Vec<MapID>,
+    // Charters whose new maps show up in the user's feed
+    #[serde(default)]
+    pub following: Vec<UserID>,
+    #[serde(default)]
+    pub feed_read: Option<DateTime<Utc>>,
     pub id: UserID,
     pub links: Vec<AccountLink>
 }
@@ -46,4 +325,22 @@ impl AccountLink {
             AccountLink::Google(id) => id.clone()
         }
     }
-}
\ No newline at end of file
+}
+
+/// Dates used as sort keys, written with a fixed number of digits so they sort as text.
+pub mod sortable_date {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn format(date: &DateTime<Utc>) -> String {
+        date.to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    pub fn serialize<S: Serializer>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(date))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        DateTime::deserialize(deserializer)
+    }
+}
@@ -1,90 +1,333 @@
 use std::collections::HashMap;
+use std::env;
 use std::io::Cursor;
-use anyhow::Error;
-use image::codecs::png::PngEncoder;
-use image::{ImageEncoder, ImageFormat, ImageReader, PixelWithColorType, Rgb, RgbImage};
+use std::str::FromStr;
+use image::error::{ImageError, LimitErrorKind};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, RgbImage};
+use serde::{Deserialize, Serialize};
 use crate::api::APIError;
-use crate::parsing::BackgroundData;
+use crate::parsing::{BackgroundData, ColorChannel};
 use crate::util::data;
 use crate::util::database::MapID;
 
-const SUPPORTED_FORMATS: [ImageFormat; 3] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Bmp];
+// Everything the game can load as a background
+const SUPPORTED_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Bmp,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+    ImageFormat::Tga,
+];
 
-pub async fn save_image(
+// Defaults for the MAX_IMAGE_WIDTH, MAX_IMAGE_HEIGHT and MAX_IMAGE_PIXELS environment variables
+pub const DEFAULT_MAX_IMAGE_SIDE: u32 = 8192;
+pub const DEFAULT_MAX_IMAGE_PIXELS: u64 = 4096 * 4096;
+// Worst case bytes per pixel the decoder may need (16-bit RGBA)
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+pub const PALETTE_SIZE: usize = 5;
+const PALETTE_SAMPLE_SIZE: u32 = 64;
+// Squared RGB distance two palette colours need to be apart
+const PALETTE_MIN_DISTANCE: u32 = 48 * 48;
+
+// Every size is stored as WebP, with a PNG fallback for browsers that can't show it
+pub const OUTPUT_FORMATS: [ImageFormat; 2] = [ImageFormat::WebP, ImageFormat::Png];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSize {
+    Thumbnail,
+    Card,
+    Full,
+}
+
+pub const IMAGE_SIZES: [ImageSize; 3] = [ImageSize::Thumbnail, ImageSize::Card, ImageSize::Full];
+
+impl ImageSize {
+    pub fn get_bounds(&self) -> Option<(u32, u32)> {
+        match self {
+            ImageSize::Thumbnail => Some((320, 180)),
+            ImageSize::Card => Some((800, 450)),
+            ImageSize::Full => None,
+        }
+    }
+
+    /// The full size keeps the old `{id}.png` key so existing links still work.
+    pub fn get_key(&self, uuid: &MapID, format: ImageFormat) -> String {
+        let extension = format.extensions_str()[0];
+        match self {
+            ImageSize::Thumbnail => format!("{uuid}_thumbnail.{extension}"),
+            ImageSize::Card => format!("{uuid}_card.{extension}"),
+            ImageSize::Full => format!("{uuid}.{extension}"),
+        }
+    }
+}
+
+/// Decodes the archive's background, or returns None if there's nothing to use.
+/// Backgrounds that can't be used are skipped with a warning for the uploader, so the map
+/// still uploads with the default background.
+pub fn read_image(
     image: &Option<Vec<u8>>,
     bg_data: &Option<BackgroundData>,
-    uuid: &MapID,
-) -> Result<(), APIError> {
+) -> Result<Option<DynamicImage>, String> {
     let Some(ref image) = image else {
-        return Ok(());
+        return Ok(None);
     };
     if image.is_empty() {
-        return Ok(());
+        return Ok(None);
+    }
+    let mut reader = ImageReader::new(Cursor::new(image))
+        .with_guessed_format()
+        .map_err(|err| format!("Failed to read the background image: {err}"))?;
+    if reader.format().is_none() {
+        // TGA has no magic bytes, so fall back to the file's extension
+        if let Some(format) = bg_data.as_ref().and_then(|bg_data| ImageFormat::from_path(&bg_data.image).ok()) {
+            reader.set_format(format);
+        }
     }
-    let reader = ImageReader::new(Cursor::new(image)).with_guessed_format()?;
     if !reader
         .format()
         .is_some_and(|format| SUPPORTED_FORMATS.contains(&format))
     {
-        return Err(APIError::ZipError(Error::msg(format!(
-            "Unknown or unsupported background image format, please use png, bmp or jpeg! {:?}",
-            reader.format()
-        ))));
+        return Err("Unsupported background image format, the default background is used instead".to_string());
     }
 
-    let image = reader
-        .decode()
-        .map_err(|err| APIError::ZipError(Error::from(err)))?;
-    let size = (image.width(), image.height());
-    let mut output = Vec::new();
-    let image = replace_image_channels(image.to_rgb8(), size, bg_data);
-    PngEncoder::new(&mut output).write_image(image.as_ref(), size.0, size.1, <Rgb<u8> as PixelWithColorType>::COLOR_TYPE)
-        .map_err(|err| APIError::ZipError(err.into()))?;
-    data().await.amazon.upload_object(output, format!("{uuid}.png").as_str()).await
-        .map_err(APIError::database_error)?;
-    Ok(())
+    decode_image(reader).map(Some)
 }
 
-fn replace_image_channels(
-    mut img_buffer: RgbImage,
-    size: (u32, u32),
-    bg_data: &Option<BackgroundData>,
-) -> RgbImage {
-    let Some(bg_data) = bg_data else {
-        return img_buffer;
-    };
-    let mut channels: HashMap<[u8; 3], [u8; 3]> = HashMap::new();
-    if let Some(channel) = &bg_data.red_channel {
-        channels.insert([255, 0, 0], channel.into());
+/// Recolors the decoded background with the level's channel colours.
+pub fn render_image(image: &DynamicImage, bg_data: &Option<BackgroundData>) -> DynamicImage {
+    DynamicImage::ImageRgb8(render_background(image.to_rgb8(), bg_data))
+}
+
+pub async fn save_image(image: &DynamicImage, uuid: &MapID) -> Result<Vec<ImageSize>, APIError> {
+    let size = (image.width(), image.height());
+    let mut sizes = vec![];
+    for image_size in IMAGE_SIZES {
+        let resized = match image_size.get_bounds() {
+            // No point storing a "smaller" copy of an image that already fits
+            Some((width, height)) if size.0 <= width && size.1 <= height => continue,
+            Some((width, height)) => image.resize(width, height, FilterType::Triangle),
+            None => image.clone(),
+        };
+        for format in OUTPUT_FORMATS {
+            let mut output = Vec::new();
+            resized.write_to(&mut Cursor::new(&mut output), format)
+                .map_err(|err| APIError::ZipError(err.into()))?;
+            data().await.amazon.upload_object(output, image_size.get_key(uuid, format).as_str()).await
+                .map_err(APIError::database_error)?;
+        }
+        sizes.push(image_size);
     }
-    if let Some(channel) = &bg_data.green_channel {
-        channels.insert([0, 255, 0], channel.into());
+    Ok(sizes)
+}
+
+/// Picks the most common colours in the background as `#rrggbb` strings, most dominant first.
+pub fn get_palette(image: &DynamicImage) -> Vec<String> {
+    // Bucket colours to 4 bits per channel, keeping the sum of each bucket to average later
+    let mut buckets: HashMap<[u8; 3], ([u64; 3], u64)> = HashMap::new();
+    for pixel in image.thumbnail(PALETTE_SAMPLE_SIZE, PALETTE_SAMPLE_SIZE).to_rgb8().pixels() {
+        let (sum, count) = buckets.entry(pixel.0.map(|value| value >> 4)).or_default();
+        for (sum, value) in sum.iter_mut().zip(pixel.0) {
+            *sum += value as u64;
+        }
+        *count += 1;
     }
-    if let Some(channel) = &bg_data.blue_channel {
-        channels.insert([0, 0, 255], channel.into());
+
+    let mut buckets: Vec<_> = buckets.into_values().collect();
+    buckets.sort_by_key(|(_, count)| *count);
+    buckets.reverse();
+    let mut palette: Vec<[u8; 3]> = vec![];
+    for (sum, count) in buckets {
+        let color = sum.map(|sum| (sum / count) as u8);
+        // Skip colours that look the same as one already picked
+        if palette.iter().any(|picked| get_color_distance(picked, &color) < PALETTE_MIN_DISTANCE) {
+            continue;
+        }
+        palette.push(color);
+        if palette.len() == PALETTE_SIZE {
+            break;
+        }
     }
-    if let Some(channel) = &bg_data.magenta_channel {
-        channels.insert([255, 0, 255], channel.into());
+    palette.into_iter()
+        .map(|[red, green, blue]| format!("#{red:02x}{green:02x}{blue:02x}"))
+        .collect()
+}
+
+/// Difference hash of the background, close images differ in only a few bits.
+pub fn get_image_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
     }
-    if let Some(channel) = &bg_data.cyan_channel {
-        channels.insert([0, 255, 255], channel.into());
+    hash
+}
+
+fn get_color_distance(first: &[u8; 3], second: &[u8; 3]) -> u32 {
+    first.iter().zip(second)
+        .map(|(first, second)| (*first as i32 - *second as i32).pow(2) as u32)
+        .sum()
+}
+
+fn decode_image(mut reader: ImageReader<Cursor<&Vec<u8>>>) -> Result<DynamicImage, String> {
+    // Checked against the header before anything is decoded, so huge images never get allocated
+    let (max_width, max_height, max_pixels) = get_image_limits();
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_width);
+    limits.max_image_height = Some(max_height);
+    limits.max_alloc = Some(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+    reader.limits(limits);
+    let decoder = reader.into_decoder().map_err(get_decode_error)?;
+    let (width, height) = decoder.dimensions();
+    if width as u64 * height as u64 > max_pixels {
+        return Err(format!(
+            "Background image is too large ({width}x{height}), it must be under {max_pixels} pixels!"
+        ));
     }
-    if let Some(channel) = &bg_data.yellow_channel {
-        channels.insert([255, 255, 0], channel.into());
+
+    // Animated GIFs and WebPs decode to their first frame
+    DynamicImage::from_decoder(decoder).map_err(get_decode_error)
+}
+
+pub fn get_image_limits() -> (u32, u32, u64) {
+    (
+        get_env_or("MAX_IMAGE_WIDTH", DEFAULT_MAX_IMAGE_SIDE),
+        get_env_or("MAX_IMAGE_HEIGHT", DEFAULT_MAX_IMAGE_SIDE),
+        get_env_or("MAX_IMAGE_PIXELS", DEFAULT_MAX_IMAGE_PIXELS),
+    )
+}
+
+fn get_env_or<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn get_decode_error(error: ImageError) -> String {
+    match error {
+        ImageError::Limits(limit) if limit.kind() == LimitErrorKind::InsufficientMemory => {
+            let (_, _, max_pixels) = get_image_limits();
+            format!("Background image needs too much memory to decode, it must be under {max_pixels} pixels!")
+        }
+        ImageError::Limits(_) => {
+            let (max_width, max_height, _) = get_image_limits();
+            format!("Background image is too large, it must be at most {max_width}x{max_height}!")
+        }
+        error => format!("Failed to decode the background image: {error}"),
     }
-    channels.insert([255, 255, 255], [255, 255, 255]);
-    let mut i = 0;
+}
+
+/// Recolors the background the same way the game does. Each pixel is split into a white
+/// part, one secondary (cyan, magenta or yellow) part and one primary (red, green or blue)
+/// part, and each part is swapped for its channel's colour before being added back up.
+/// Channels the level doesn't set keep their original colour.
+fn render_background(mut img_buffer: RgbImage, bg_data: &Option<BackgroundData>) -> RgbImage {
+    let Some(bg_data) = bg_data else {
+        return img_buffer;
+    };
+    let channel = |channel: &Option<ColorChannel>, default: [u8; 3]| -> [f32; 3] {
+        channel.as_ref().map_or(default, Into::into).map(|value| value as f32)
+    };
+    let red = channel(&bg_data.red_channel, [255, 0, 0]);
+    let green = channel(&bg_data.green_channel, [0, 255, 0]);
+    let blue = channel(&bg_data.blue_channel, [0, 0, 255]);
+    let cyan = channel(&bg_data.cyan_channel, [0, 255, 255]);
+    let magenta = channel(&bg_data.magenta_channel, [255, 0, 255]);
+    let yellow = channel(&bg_data.yellow_channel, [255, 255, 0]);
+
     for pixel in img_buffer.pixels_mut() {
-        if let Some(replacement) = channels.get(&pixel.0) {
-            pixel.0 = *replacement;
-        } else {
-            pixel.0 = if ((i % size.0) % 2 == 0) && (i / size.0) % 2 == 0 {
-                [0, 0, 0]
-            } else {
-                [255, 0, 255]
+        let [r, g, b] = pixel.0.map(|value| value as f32 / 255.0);
+        let white = r.min(g).min(b);
+        let (r, g, b) = (r - white, g - white, b - white);
+        // At most one of these is non-zero, since white removed the smallest component
+        let (yellow_weight, cyan_weight, magenta_weight) = (r.min(g), g.min(b), r.min(b));
+        let secondary = yellow_weight + cyan_weight + magenta_weight;
+        let (r, g, b) = (r - secondary, g - secondary, b - secondary);
+
+        let mut output = [white * 255.0; 3];
+        for (weight, color) in [
+            (yellow_weight, yellow),
+            (cyan_weight, cyan),
+            (magenta_weight, magenta),
+            (r.max(0.0), red),
+            (g.max(0.0), green),
+            (b.max(0.0), blue),
+        ] {
+            for (output, color) in output.iter_mut().zip(color) {
+                *output += weight * color;
             }
         }
-        i += 1;
+        pixel.0 = output.map(|value| value.round().clamp(0.0, 255.0) as u8);
     }
     img_buffer
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn palette_is_ordered_by_area() {
+        let mut image = RgbImage::from_pixel(100, 100, Rgb([255, 0, 0]));
+        for x in 0..30 {
+            for y in 0..100 {
+                image.put_pixel(x, y, Rgb([0, 0, 250]));
+            }
+        }
+        assert_eq!(get_palette(&DynamicImage::ImageRgb8(image)), vec!["#ff0000", "#0000fa"]);
+    }
+
+    #[test]
+    fn palette_skips_close_colours() {
+        let mut image = RgbImage::from_pixel(100, 100, Rgb([200, 200, 200]));
+        for x in 0..40 {
+            for y in 0..100 {
+                image.put_pixel(x, y, Rgb([210, 210, 210]));
+            }
+        }
+        assert_eq!(get_palette(&DynamicImage::ImageRgb8(image)).len(), 1);
+    }
+
+    #[test]
+    fn palette_has_at_most_palette_size_colours() {
+        let mut image = RgbImage::new(80, 80);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            pixel.0 = [(x / 10 * 32) as u8, (y / 10 * 32) as u8, 128];
+        }
+        assert_eq!(get_palette(&DynamicImage::ImageRgb8(image)).len(), PALETTE_SIZE);
+    }
+
+    #[test]
+    fn read_image_keeps_original_colours() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 16, Rgb([255, 0, 0])));
+        let bg_data: Option<BackgroundData> = Some(serde_json::from_str(
+            r#"{"image": "bg.png", "redChannel": {"r": 0, "g": 0, "b": 255}}"#,
+        ).unwrap());
+        let decoded = read_image(&Some(encode_png(&image)), &bg_data).unwrap().unwrap();
+        assert_eq!(decoded.to_rgb8().get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(render_image(&decoded, &bg_data).to_rgb8().get_pixel(0, 0).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn unsupported_background_is_a_warning() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(4, 4));
+        let mut output = Vec::new();
+        image.write_to(&mut Cursor::new(&mut output), ImageFormat::Tiff).unwrap();
+        assert!(read_image(&Some(output), &None).is_err());
+        assert!(read_image(&Some(b"not an image".to_vec()), &None).is_err());
+        assert!(read_image(&None, &None).unwrap().is_none());
+    }
+
+    fn encode_png(image: &DynamicImage) -> Vec<u8> {
+        let mut output = Vec::new();
+        image.write_to(&mut Cursor::new(&mut output), ImageFormat::Png).unwrap();
+        output
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::HashSet;
 use crate::api::APIError;
-use crate::util::amazon::{setup, USERS_TABLE_NAME};
-use crate::util::database::{AccountLink, BeatMap, User};
+use crate::util::amazon::{setup, MAPS_TABLE_NAME, USERS_TABLE_NAME};
+use crate::parsing::merge_archives;
+use crate::util::database::{get_pack_key, AccountLink, BeatMap, PackID, User};
 use crate::SiteData;
 use std::sync::{Arc, LockResult};
 use firebase_auth::FirebaseAuth;
@@ -11,15 +12,19 @@ use lazy_static::lazy_static;
 use crate::util::ratelimiter::Ratelimiter;
 
 pub mod amazon;
+pub mod archive;
 pub mod database;
 pub mod ratelimiter;
 pub mod warp;
 pub mod data;
 pub mod image;
+pub mod share;
 
 static mut DATA: Option<SiteData> = None;
 lazy_static! {
     static ref DATA_MUTEX: Mutex<()> = Mutex::new(());
+    // Rebuilds run one at a time, so the last one always sees the newest maps
+    static ref PACK_MUTEX: Mutex<()> = Mutex::new(());
 }
 
 pub async fn data() -> SiteData {
@@ -72,6 +77,45 @@ pub async fn get_or_create_user<F: Fn() -> User>(
     Ok(user)
 }
 
+/// Rebuilds the pack in the background, since it downloads every archive in it.
+pub fn schedule_pack_update(pack_id: PackID) {
+    tokio::spawn(async move {
+        let _lock = PACK_MUTEX.lock().await;
+        if let Err(err) = update_pack(&pack_id).await {
+            println!("Failed to update pack {pack_id}: {err:?}");
+        }
+    });
+}
+
+/// Rebuilds the pack's archive from the maps still in it, deleting it once none are left.
+async fn update_pack(pack_id: &PackID) -> Result<(), APIError> {
+    let maps: Vec<BeatMap> = data().await.amazon
+        .query(MAPS_TABLE_NAME, "pack_id", pack_id.to_string())
+        .await
+        .map_err(APIError::database_error)?;
+    if maps.is_empty() {
+        return data().await.amazon
+            .delete_object(get_pack_key(pack_id).as_str())
+            .await
+            .map(|_| ())
+            .map_err(APIError::database_error);
+    }
+
+    let mut archives = vec![];
+    for map in maps {
+        archives.push(data().await.amazon
+            .get_object(map.get_archive_key().as_str())
+            .await
+            .map_err(APIError::database_error)?);
+    }
+    let pack = merge_archives(&archives).map_err(APIError::ZipError)?;
+    data().await.amazon
+        .upload_object(pack, get_pack_key(pack_id).as_str())
+        .await
+        .map_err(APIError::database_error)?;
+    Ok(())
+}
+
 pub fn get_search_combos(song: &BeatMap) -> Vec<String> {
     let mut output = HashSet::new();
     add_word_combos(&song.song, &mut output);
@@ -5,6 +5,7 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ops::Add;
 use std::time::SystemTime;
 use crate::api::APIError;
+use crate::util::database::UserID;
 
 pub struct Ratelimiter {
     limits: HashMap<SiteAction, Limits>,
@@ -45,6 +46,8 @@ pub enum UniqueIdentifier {
     Ipv4(Ipv4Addr),
     Ipv6(Ipv6Addr),
     Discord(u64),
+    // For actions limited per account instead of per connection
+    User(UserID),
 }
 
 impl Limits {
@@ -70,12 +73,14 @@ impl Limits {
     }
 }
 
-pub const ACTIONS: [SiteAction; 5] = [
+pub const ACTIONS: [SiteAction; 7] = [
     SiteAction::Search,
     SiteAction::Upload,
     SiteAction::Update,
     SiteAction::UpvoteList,
     SiteAction::Download,
+    SiteAction::Bundle,
+    SiteAction::Comment,
 ];
 
 #[derive(Eq, PartialEq, Hash, Ord, PartialOrd, Copy, Clone)]
@@ -85,6 +90,8 @@ pub enum SiteAction {
     Update,
     Upload,
     UpvoteList,
+    Bundle,
+    Comment,
 }
 
 impl SiteAction {
@@ -92,7 +99,8 @@ impl SiteAction {
     pub fn get_limit(&self) -> f64 {
         match self {
             SiteAction::Search | SiteAction::Download | SiteAction::UpvoteList => 0.25,
-            SiteAction::Update => 60.0,
+            SiteAction::Comment => 15.0,
+            SiteAction::Update | SiteAction::Bundle => 60.0,
             SiteAction::Upload => 60.0 * 60.0 * 12.0,
         }
     }
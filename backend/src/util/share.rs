@@ -0,0 +1,102 @@
+use crate::util::database::{BeatMap, MapID};
+use ab_glyph::{FontVec, PxScale};
+use anyhow::Error;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+use lazy_static::lazy_static;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+
+// The size Discord, Twitter and friends expect for large previews
+pub const SHARE_CARD_WIDTH: u32 = 1200;
+pub const SHARE_CARD_HEIGHT: u32 = 630;
+const MARGIN: i32 = 60;
+const DEFAULT_FONT: &'static str = "/usr/share/fonts/truetype/dejavu/DejaVuSans-Bold.ttf";
+
+lazy_static! {
+    static ref FONT: Option<FontVec> = load_font();
+}
+
+fn load_font() -> Option<FontVec> {
+    let path = env::var("SHARE_CARD_FONT").unwrap_or(DEFAULT_FONT.to_string());
+    match fs::read(&path)
+        .map_err(Error::from)
+        .and_then(|font| FontVec::try_from_vec(font).map_err(Error::from))
+    {
+        Ok(font) => Some(font),
+        Err(error) => {
+            println!("Failed to load share card font {path}: {error:?}");
+            None
+        }
+    }
+}
+
+pub fn get_share_card_key(uuid: &MapID) -> String {
+    format!("{uuid}_share.png")
+}
+
+/// Renders the preview card shown when a map link is posted, or None if no font is installed.
+pub fn render_share_card(map: &BeatMap, background: Option<&DynamicImage>) -> Result<Option<Vec<u8>>, Error> {
+    let Some(font) = FONT.as_ref() else {
+        return Ok(None);
+    };
+    let mut card = match background {
+        Some(background) => background
+            .resize_to_fill(SHARE_CARD_WIDTH, SHARE_CARD_HEIGHT, FilterType::Triangle)
+            .to_rgb8(),
+        None => RgbImage::from_pixel(SHARE_CARD_WIDTH, SHARE_CARD_HEIGHT, Rgb([24, 24, 32])),
+    };
+    // Darken the background so the text stays readable
+    for pixel in card.pixels_mut() {
+        pixel.0 = pixel.0.map(|value| (value as u16 * 2 / 5) as u8);
+    }
+
+    let white = Rgb([255, 255, 255]);
+    let grey = Rgb([200, 200, 200]);
+    draw_text_mut(&mut card, white, MARGIN, 170, 84.0, font, &fit_text(&map.song, 84.0, font));
+    draw_text_mut(&mut card, grey, MARGIN, 280, 48.0, font, &fit_text(&map.artist, 48.0, font));
+    draw_text_mut(&mut card, grey, MARGIN, 350, 40.0, font,
+                  &fit_text(&format!("Charted by {}", map.charter), 40.0, font));
+
+    let mut x = MARGIN;
+    for variant in &map.difficulties {
+        let label = format!("{} {}", variant.display, variant.difficulty);
+        let (width, _) = text_size(32.0, font, &label);
+        if x + width as i32 + 32 > SHARE_CARD_WIDTH as i32 - MARGIN {
+            break;
+        }
+        draw_filled_rect_mut(&mut card, Rect::at(x, 470).of_size(width + 32, 60), get_badge_color(&variant.display));
+        draw_text_mut(&mut card, white, x + 16, 482, 32.0, font, &label);
+        x += width as i32 + 48;
+    }
+
+    let mut output = Vec::new();
+    card.write_to(&mut Cursor::new(&mut output), ImageFormat::Png)?;
+    Ok(Some(output))
+}
+
+fn fit_text(text: &str, scale: f32, font: &FontVec) -> String {
+    let max_width = SHARE_CARD_WIDTH - 2 * MARGIN as u32;
+    let scale = PxScale::from(scale);
+    if text_size(scale, font, text).0 <= max_width {
+        return text.to_string();
+    }
+    let mut text: Vec<char> = text.chars().collect();
+    while !text.is_empty() && text_size(scale, font, &format!("{}…", String::from_iter(&text))).0 > max_width {
+        text.pop();
+    }
+    format!("{}…", String::from_iter(text))
+}
+
+fn get_badge_color(display: &str) -> Rgb<u8> {
+    Rgb(match display {
+        "Easy" => [46, 160, 67],
+        "Hard" => [210, 140, 20],
+        "Challenge" => [200, 40, 60],
+        "Apocrypha" => [120, 50, 180],
+        _ => [40, 110, 200],
+    })
+}
@@ -54,6 +54,18 @@ pub fn handle_auth() -> impl Filter<Extract = (User,), Error = Rejection> + Copy
         })
 }
 
+/// The user whose token is in the Authorization header, for GET requests that change with who's asking.
+pub fn optional_user() -> impl Filter<Extract = (Option<User>,), Error = Rejection> + Copy {
+    warp::header::optional::<String>("authorization")
+        .and_then(|token: Option<String>| async move {
+            let Some(token) = token else {
+                return Ok::<Option<User>, Rejection>(None);
+            };
+            let token = token.strip_prefix("Bearer ").unwrap_or(&token).to_string();
+            Ok(Some(get_user(token).await.map_err(reject::custom)?))
+        })
+}
+
 pub async fn get_user(token: String) -> Result<User, APIError> {
     let user_id: UserToken = data().await.amazon.query_one(TOKENS_TABLE_NAME, "user_token", token)
         .await
@@ -65,13 +77,22 @@ pub async fn get_user(token: String) -> Result<User, APIError> {
         .ok_or(APIError::AuthError("Invalid token!".to_string()))
 }
 
-async fn get_map(id: String) -> Result<BeatMap, APIError> {
+pub async fn get_map(id: String) -> Result<BeatMap, APIError> {
     data().await.amazon.query_one(MAPS_TABLE_NAME, "id", id)
         .await
         .map_err(APIError::database_error)?
         .ok_or(APIError::AuthError("Invalid map!".to_string()))
 }
 
+/// Makes browsers save the response as `download_name`, non-ASCII names are kept for browsers that support them.
+pub fn get_content_disposition(download_name: &str) -> String {
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        download_name.replace(|c: char| !c.is_ascii() || c.is_ascii_control() || c == '"', "_"),
+        urlencoding::encode(download_name)
+    )
+}
+
 pub trait Replyable {
     fn reply(self) -> impl Reply;
 }
@@ -27,5 +27,6 @@ sleep 5
 
 #nginx &
 
+export DOMAIN MAX_IMAGE_WIDTH MAX_IMAGE_HEIGHT MAX_IMAGE_PIXELS
 exec /usr/local/backend "0.0.0.0:$PORT" /usr/local/site 1>&2
 echo -e "Done!"
\ No newline at end of file